@@ -2,13 +2,17 @@ use std::cmp::min;
 
 use time::SteadyTime;
 
+use error::Error as RotorError;
 
-#[derive(PartialEq, Eq, Debug)]
+
+#[derive(Debug)]
 #[must_use]
 pub enum Async<M, V> {
     Continue(M, V),
     Stop,
     Timeout(M, SteadyTime),
+    /// Same as `Stop` but the state machine failed with the error
+    Error(RotorError),
 }
 
 impl<M, V> Async<M, V> {
@@ -23,7 +27,9 @@ impl<M, V> Async<M, V> {
                 Continue(m, v) => Continue(m, v),
                 Stop => Stop,
                 Timeout(m, t2) => Timeout(m, min(t1, t2)),
+                Error(e) => Error(e),
             },
+            Error(e) => Error(e),
         }
     }
     pub fn map<T, F: FnOnce(M) -> T>(self, f: F) -> Async<T, V> {
@@ -32,6 +38,7 @@ impl<M, V> Async<M, V> {
             Continue(m, v) => Continue(f(m), v),
             Stop => Stop,
             Timeout(m, t) => Timeout(f(m), t),
+            Error(e) => Error(e),
         }
     }
     pub fn map_result<R, F: FnOnce(V) -> R>(self, f: F) -> Async<M, R> {
@@ -40,6 +47,7 @@ impl<M, V> Async<M, V> {
             Continue(m, v) => Continue(m, f(v)),
             Stop => Stop,
             Timeout(m, t) => Timeout(m, t),
+            Error(e) => Error(e),
         }
     }
     pub fn done<R, F: FnOnce(M) -> R>(self, f: F) -> Option<R> {
@@ -48,6 +56,7 @@ impl<M, V> Async<M, V> {
             Continue(m, _) => Some(f(m)),
             Stop => None,
             Timeout(m, _) => Some(f(m)),
+            Error(_) => None,
         }
     }
 }
@@ -59,10 +68,33 @@ impl<M> Async<M, Option<M>> {
             Continue(m, v) => Continue(f(m), v.map(f)),
             Stop => Stop,
             Timeout(m, t) => Timeout(f(m), t),
+            Error(e) => Error(e),
+        }
+    }
+}
+
+/// Errors are compared by their message, as `Error` is not comparable
+impl<M: PartialEq, V: PartialEq> PartialEq for Async<M, V> {
+    fn eq(&self, other: &Async<M, V>) -> bool {
+        use self::Async::*;
+        match (self, other) {
+            (Continue(m1, v1), Continue(m2, v2)) => {
+                m1 == m2 && v1 == v2
+            }
+            (Stop, Stop) => true,
+            (Timeout(m1, t1), Timeout(m2, t2)) => {
+                m1 == m2 && t1 == t2
+            }
+            (Error(e1), Error(e2)) => {
+                e1.to_string() == e2.to_string()
+            }
+            _ => false,
         }
     }
 }
 
+impl<M: Eq, V: Eq> Eq for Async<M, V> {}
+
 #[macro_export]
 macro_rules! async_try {
     ($e:expr) => {
@@ -73,6 +105,27 @@ macro_rules! async_try {
             => $crate::async::Async::Timeout(m, t),
             $crate::async::Async::Stop
             => return $crate::async::Async::Stop,
+            $crate::async::Async::Error(e)
+            => return $crate::async::Async::Error(e),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use Error;
+    use super::Async;
+
+    #[test]
+    fn compare() {
+        let stop: Async<u8, ()> = Async::Stop;
+        assert_eq!(stop, Async::Stop);
+        assert_eq!(Async::Continue(1u8, ()), Async::Continue(1, ()));
+        assert!(Async::Continue(1u8, ()) != Async::Continue(2, ()));
+        assert_eq!(Async::<u8, ()>::Error(Error::Timeout),
+            Async::Error(Error::Timeout));
+        assert!(Async::<u8, ()>::Error(Error::Timeout)
+            != Async::Error(Error::BufferLimit));
+        assert!(Async::<u8, ()>::Error(Error::Timeout) != Async::Stop);
+    }
+}
@@ -10,7 +10,7 @@ pub fn find_substr<B:AsRef<[u8]>, N:AsRef<[u8]>>(haystack: B, needle: N)
 {
     let haystack = haystack.as_ref();
     let needle = needle.as_ref();
-    debug_assert!(needle.len() > 0);
+    debug_assert!(!needle.is_empty());
     if needle.len() > haystack.len() {
         return None;
     }
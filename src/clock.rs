@@ -0,0 +1,77 @@
+//! Source of time for the main loop
+//!
+//! All the deadlines of the state machines are compared against the clock
+//! of the `Handler`. Use `ManualClock` to test timeouts without sleeping.
+use std::sync::{Arc, Mutex};
+
+use time::{SteadyTime, Duration};
+
+
+pub trait Clock: Send {
+    /// Current time, the `Scope::now()` of every action is taken from here
+    fn now(&self) -> SteadyTime;
+}
+
+/// The monotonic clock of the system, this is the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+/// Clock which only moves when advanced
+///
+/// Clones share the time, so one clone is passed to the handler and the
+/// test advances the other one. Call `Handler::fire_timers` after advancing
+/// the clock to run the expired timers.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: SteadyTime,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SteadyTime {
+        SteadyTime::now()
+    }
+}
+
+impl ManualClock {
+    /// Creates clock stopped at the current system time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: SteadyTime::now(),
+            offset: Arc::new(Mutex::new(Duration::zero())),
+        }
+    }
+    /// Moves the clock forward
+    pub fn advance(&self, duration: Duration) {
+        let mut offset = self.offset.lock().expect("clock is not poisoned");
+        *offset = *offset + duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SteadyTime {
+        self.start + *self.offset.lock().expect("clock is not poisoned")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::Duration;
+    use super::{Clock, ManualClock};
+
+    #[test]
+    fn advance_clones() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let copy = clock.clone();
+        copy.advance(Duration::seconds(5));
+        assert_eq!(clock.now() - start, Duration::seconds(5));
+        assert_eq!(copy.now(), clock.now());
+    }
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::cmp::min;
+
+use libc;
+use mio::{EventLoop, EventLoopConfig};
+
+/// Slab capacity used when the limit on file descriptors can't be read
+pub const DEFAULT_SLAB_CAPACITY: usize = 4096;
+/// Upper bound of slab capacity derived from the file descriptor limit
+///
+/// Slab is preallocated, so huge (or infinite) limit would waste a lot of
+/// memory. Set the capacity explicitly if you need more state machines.
+pub const MAX_DEFAULT_SLAB_CAPACITY: usize = 262144;
+/// Default number of bytes read or written by a stream in one event
+pub const DEFAULT_IO_BUDGET: usize = 65536;
+/// Default number of slots (milliseconds) in the timer wheel
+pub const DEFAULT_TIMER_WHEEL_SIZE: usize = 4096;
+/// Default number of io events processed in one loop iteration
+///
+/// This is how many events mio reads in one poll, so by default none of
+/// them are deferred.
+pub const DEFAULT_MAX_EVENTS_PER_TICK: usize = 1024;
+
+
+/// Configuration of the main loop
+///
+/// Use `event_loop()` to create mio loop and `Handler::configured()` to
+/// create the handler for it. All the setters return `&mut Self` so they
+/// can be chained.
+#[derive(Clone, Debug)]
+pub struct HandlerConfig {
+    pub(crate) slab_capacity: usize,
+    pub(crate) io_budget: usize,
+    pub(crate) timer_wheel_size: usize,
+    pub(crate) max_events_per_tick: usize,
+    mio: EventLoopConfig,
+}
+
+impl HandlerConfig {
+    /// Returns default configuration
+    ///
+    /// Slab capacity is derived from the `RLIMIT_NOFILE` as each state
+    /// machine usually owns at least one file descriptor.
+    pub fn new() -> HandlerConfig {
+        HandlerConfig {
+            slab_capacity: slab_capacity_from_rlimit(),
+            io_budget: DEFAULT_IO_BUDGET,
+            timer_wheel_size: DEFAULT_TIMER_WHEEL_SIZE,
+            max_events_per_tick: DEFAULT_MAX_EVENTS_PER_TICK,
+            mio: EventLoopConfig::default(),
+        }
+    }
+    /// Maximum number of state machines in the main loop
+    pub fn slab_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.slab_capacity = capacity;
+        self
+    }
+    /// Bytes a state machine reads or writes in one event before yielding
+    ///
+    /// This keeps single fast connection from starving others. Streams
+    /// may override it with `StreamConfig::io_budget`.
+    pub fn io_budget(&mut self, bytes: usize) -> &mut Self {
+        self.io_budget = bytes;
+        self
+    }
+    /// Number of slots in the timer wheel, each slot is a millisecond
+    ///
+    /// There is no limit on the number of timers, but the ones further
+    /// than the size of the wheel are revisited on each turn of it.
+    pub fn timer_wheel_size(&mut self, size: usize) -> &mut Self {
+        self.timer_wheel_size = size;
+        self
+    }
+    /// Maximum number of io events processed in single loop iteration
+    ///
+    /// The rest are deferred to the next iterations, so notifications and
+    /// timers are not delayed by a flood of io events. Zero means one.
+    pub fn max_events_per_tick(&mut self, num: usize) -> &mut Self {
+        self.max_events_per_tick = num;
+        self
+    }
+    /// Capacity of the notification queue (see `Notifier`)
+    pub fn notify_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.mio.notify_capacity = capacity;
+        self
+    }
+    /// Maximum number of notifications processed in single loop iteration
+    pub fn messages_per_tick(&mut self, num: usize) -> &mut Self {
+        self.mio.messages_per_tick = num;
+        self
+    }
+    /// Creates event loop configured with this settings
+    pub fn event_loop<H: ::mio::Handler>(&self) -> io::Result<EventLoop<H>> {
+        EventLoop::configured(self.mio)
+    }
+}
+
+impl Default for HandlerConfig {
+    fn default() -> HandlerConfig {
+        HandlerConfig::new()
+    }
+}
+
+fn slab_capacity_from_rlimit() -> usize {
+    let mut lim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    let res = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) };
+    if res != 0 {
+        warn!("Can't get RLIMIT_NOFILE: {}, using default slab capacity",
+            io::Error::last_os_error());
+        return DEFAULT_SLAB_CAPACITY;
+    }
+    if lim.rlim_cur == libc::RLIM_INFINITY {
+        return MAX_DEFAULT_SLAB_CAPACITY;
+    }
+    min(lim.rlim_cur as usize, MAX_DEFAULT_SLAB_CAPACITY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HandlerConfig, MAX_DEFAULT_SLAB_CAPACITY};
+
+    #[test]
+    fn default_slab_capacity() {
+        let cap = HandlerConfig::new().slab_capacity;
+        assert!(cap > 0);
+        assert!(cap <= MAX_DEFAULT_SLAB_CAPACITY);
+    }
+}
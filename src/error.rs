@@ -0,0 +1,108 @@
+use std::io;
+use std::fmt;
+use std::error::Error as StdError;
+use std::io::ErrorKind::ConnectionReset;
+
+use handler::Abort;
+
+
+/// Error which terminated the connection (or other state machine)
+///
+/// Passed to the `error_happened` callbacks of the protocols, so it can be
+/// inspected and counted in the context.
+#[derive(Debug)]
+pub enum Error {
+    /// Error reading from the socket
+    Read(io::Error),
+    /// Error writing to the socket
+    Write(io::Error),
+    /// Error establishing outgoing connection
+    Connect(io::Error),
+    /// Error accepting incoming connection
+    Accept(io::Error),
+    /// Connection is not established (or other deadline passed) in time
+    Timeout,
+    /// Connection reset by peer
+    PeerReset,
+    /// Buffer has grown larger than configured limit
+    BufferLimit,
+    /// The state machine can't be added to the main loop
+    Register(Abort),
+    /// Error raised by the protocol itself
+    Protocol(Box<dyn StdError + Send + Sync>),
+}
+
+impl Error {
+    /// Wraps an error of the protocol
+    pub fn protocol<E>(err: E) -> Error
+        where E: Into<Box<dyn StdError + Send + Sync>>
+    {
+        Error::Protocol(err.into())
+    }
+    pub(crate) fn read(err: io::Error) -> Error {
+        match err.kind() {
+            ConnectionReset => Error::PeerReset,
+            _ => Error::Read(err),
+        }
+    }
+    pub(crate) fn write(err: io::Error) -> Error {
+        match err.kind() {
+            ConnectionReset => Error::PeerReset,
+            _ => Error::Write(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match *self {
+            Read(ref e) => write!(f, "read error: {}", e),
+            Write(ref e) => write!(f, "write error: {}", e),
+            Connect(ref e) => write!(f, "connect error: {}", e),
+            Accept(ref e) => write!(f, "accept error: {}", e),
+            Timeout => write!(f, "timed out"),
+            PeerReset => write!(f, "connection reset by peer"),
+            BufferLimit => write!(f, "buffer limit exceeded"),
+            Register(reason) => {
+                write!(f, "can't add to the loop: {:?}", reason)
+            }
+            Protocol(ref e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        use self::Error::*;
+        match *self {
+            Read(ref e) | Write(ref e) | Connect(ref e) | Accept(ref e)
+            => Some(e),
+            Protocol(ref e) => Some(&**e),
+            Timeout | PeerReset | BufferLimit | Register(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::error::Error as StdError;
+    use super::Error;
+
+    #[test]
+    fn reset_by_peer() {
+        let err = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        match Error::read(err) {
+            Error::PeerReset => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn protocol_error() {
+        let err = Error::protocol("bad request");
+        assert_eq!(err.to_string(), "protocol error: bad request");
+        assert_eq!(err.source().unwrap().to_string(), "bad request");
+    }
+}
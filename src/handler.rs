@@ -1,175 +1,893 @@
-use std::cmp::max;
+use std::io;
+use std::cmp::{min, max};
+use std::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
 
-use time::SteadyTime;
+use time::{SteadyTime, Duration};
 
 use mio::{self, EventLoop, Token, EventSet, Evented, PollOpt};
 use mio::util::Slab;
 
-use {Async};
+use {Async, Error};
+use config::HandlerConfig;
+use clock::{Clock, SystemClock};
+use notify::{Notifier, Channel, Mailbox};
+use scope::Scope;
+use timer::{Timer, Timers, Wheel};
 
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Abort {
+    /// There is no free slot in the slab for the state machine
     NoSlabSpace,
+    /// Registration of some file descriptor of the machine failed
     RegisterFailed,
+    /// State machine returned `Stop` when registering
     MachineAddError,
 }
 
 pub enum Timeo {
-    Fsm(Token),
+    /// Wakes up the loop when the earliest timer in the wheel expires
+    Wheel,
 }
 
+#[derive(Debug)]
 pub enum Notify {
-    Fsm(Token),
+    /// Wakes up the machine, the generation tells apart the machines which
+    /// reused the same slot
+    Fsm(Token, u64),
+    /// Calls `ready` with empty event set, see `Scope::reschedule`
+    Ready(Token, u64),
+    /// Starts graceful shutdown, see `Handler::shutdown_gracefully`
+    Shutdown(SteadyTime),
+    /// Detaches the machine, see `Notifier::detach`
+    Detach(Token, u64),
+    /// Adds the machines sent to the `Mailbox` of the loop
+    Inbox,
+    /// Processes io events deferred by `HandlerConfig::max_events_per_tick`
+    Deferred,
 }
 
-pub struct Cell<M:Sized>(M, Option<(SteadyTime, mio::Timeout)>);
+/// Result of the graceful shutdown of the loop
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// State machines which stopped themselves before the deadline
+    pub finished: usize,
+    /// State machines dropped when the deadline passed
+    pub dropped: usize,
+    /// State machines detached (e.g. moved to another loop) meanwhile
+    pub detached: usize,
+    /// Time from the shutdown request until the loop stopped
+    pub elapsed: Duration,
+}
+
+struct Shutdown {
+    started: SteadyTime,
+    deadline: SteadyTime,
+    /// Machines alive at the start, and those added since
+    total: usize,
+    detached: usize,
+}
+
+pub struct Cell<M:Sized>(M, u64, Timers, Interest);
+
+/// Interest requested by the last `register` or `reregister` hook of the
+/// machine, in the order of the calls to the `Registrator`
+type Interest = Vec<(EventSet, PollOpt)>;
+
+/// Receives the machines detached by `Notifier::detach`
+type DetachFn<C, M> = Box<dyn FnMut(M, &mut C) + Send>;
 
 pub struct Handler<Ctx, M>
     where M: EventMachine<Ctx>
 {
     slab: Slab<Cell<M>>,
+    generation: u64,
+    io_budget: usize,
+    errors: u64,
+    /// Io events processed in single loop iteration, see `HandlerConfig`
+    max_events: usize,
+    /// Io events processed in the current iteration
+    events: usize,
+    /// Tokens of the deferred io events in the order they came
+    deferred: VecDeque<Token>,
+    /// Generation of the machine and the deferred events of each token
+    pending: HashMap<Token, (u64, EventSet)>,
+    wheel: Wheel,
+    /// The mio timeout which wakes up the loop for the wheel
+    wakeup: Option<(SteadyTime, mio::Timeout)>,
+    clock: Box<dyn Clock>,
+    shutdown: Option<Shutdown>,
+    summary: Option<ShutdownSummary>,
+    /// Machines moved from other loops, see `Handler::mailbox`
+    inbox: mpsc::Receiver<M>,
+    inbox_sender: mpsc::Sender<M>,
+    on_detach: Option<DetachFn<Ctx, M>>,
     context: Ctx,
 }
 
-pub trait Registrator {
-    fn register(&mut self, io: &Evented, interest: EventSet, opt: PollOpt);
+/// Operations of the main loop needed by state machines
+///
+/// This is mostly a type-erased `EventLoop<Handler<C, M>>`, so that `Scope`
+/// and `Registrator` don't depend on the type of the root state machine.
+pub trait LoopApi {
+    fn register(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>;
+    fn reregister(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>;
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()>;
+    fn channel(&self) -> Channel;
+    fn shutdown(&mut self);
 }
 
-struct Reg<'a, H>
-    where H: mio::Handler + 'a, H::Timeout: 'a, H::Message: 'a
+impl<H> LoopApi for EventLoop<H>
+    where H: mio::Handler<Timeout=Timeo, Message=Notify>
 {
-    eloop: &'a mut EventLoop<H>,
+    fn register(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        self.register_opt(io, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        EventLoop::reregister(self, io, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()> {
+        EventLoop::deregister(self, io)
+    }
+    fn channel(&self) -> Channel {
+        Channel::Loop(EventLoop::channel(self))
+    }
+    fn shutdown(&mut self) {
+        EventLoop::shutdown(self)
+    }
+}
+
+pub trait Registrator {
+    /// Registers file descriptor in the main loop
+    ///
+    /// If registration fails, the state machine is not added to the main
+    /// loop regardless of what is returned from `EventMachine::register`
+    fn register(&mut self, io: &dyn Evented, interest: EventSet, opt: PollOpt)
+        -> io::Result<()>;
+    /// Changes interest of already registered file descriptor
+    ///
+    /// May also be used to take over file descriptor registered by another
+    /// state machine (i.e. when it's passed to the child)
+    fn reregister(&mut self, io: &dyn Evented,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>;
+    /// Removes file descriptor from the main loop
+    ///
+    /// Useful when descriptor is shared or duplicated, otherwise closing it
+    /// is enough for descriptor to be removed
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()>;
+    /// Returns a notifier which may be used to wake up the state machine
+    /// being registered, including from other threads
+    fn notifier(&mut self) -> Notifier;
+    /// Current time of the loop, use it to calculate deadlines
+    fn now(&self) -> SteadyTime;
+}
+
+struct Reg<'a> {
+    eloop: &'a mut dyn LoopApi,
     token: Token,
+    generation: u64,
+    now: SteadyTime,
+    interest: Interest,
+    /// Number of `register` and `reregister` calls so far
+    calls: usize,
+    /// Some descriptor was deregistered, so `interest` can't be trusted
+    stale: bool,
+    failed: bool,
 }
 
-impl<'a, H> Registrator for Reg<'a, H>
-    where H: mio::Handler + 'a, H::Timeout: 'a, H::Message: 'a
-{
-    fn register(&mut self, io: &Evented, interest: EventSet, opt: PollOpt)
+impl<'a> Reg<'a> {
+    fn new(eloop: &'a mut dyn LoopApi, token: Token, generation: u64,
+        now: SteadyTime, interest: Interest)
+        -> Reg<'a>
+    {
+        Reg { eloop, token, generation, now, interest,
+              calls: 0, stale: false, failed: false }
+    }
+    /// Records the interest of the call, returns true if it's unchanged
+    fn record(&mut self, interest: EventSet, opt: PollOpt) -> bool {
+        let index = self.calls;
+        self.calls += 1;
+        match self.interest.get_mut(index) {
+            Some(old) => {
+                let same = *old == (interest, opt);
+                *old = (interest, opt);
+                same
+            }
+            None => {
+                self.interest.push((interest, opt));
+                false
+            }
+        }
+    }
+    /// Returns whether any call failed and the interest to keep
+    fn finish(self) -> (bool, Interest) {
+        let Reg { mut interest, calls, stale, failed, .. } = self;
+        if stale || failed {
+            interest.clear();
+        } else {
+            interest.truncate(calls);
+        }
+        (failed, interest)
+    }
+}
+
+impl<'a> Registrator for Reg<'a> {
+    fn register(&mut self, io: &dyn Evented, interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.record(interest, opt);
+        self.eloop.register(io, self.token, interest, opt)
+        .map_err(|e| {
+            error!("Error registering {:?}: {}", self.token, e);
+            self.failed = true;
+            e
+        })
+    }
+    fn reregister(&mut self, io: &dyn Evented,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
     {
-        self.eloop.register_opt(io, self.token, interest, opt).unwrap();
+        if self.record(interest, opt) {
+            return Ok(());
+        }
+        self.eloop.reregister(io, self.token, interest, opt)
+        .map_err(|e| {
+            error!("Error reregistering {:?}: {}", self.token, e);
+            self.failed = true;
+            e
+        })
+    }
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()> {
+        self.stale = true;
+        self.eloop.deregister(io)
+        .map_err(|e| {
+            error!("Error deregistering {:?}: {}", self.token, e);
+            self.failed = true;
+            e
+        })
+    }
+    fn notifier(&mut self) -> Notifier {
+        Notifier::new(self.token, self.generation, self.eloop.channel())
+    }
+    fn now(&self) -> SteadyTime {
+        self.now
     }
 }
 
 pub trait EventMachine<C>: Sized {
     /// Socket readiness notification
-    fn ready(self, events: EventSet, context: &mut C)
+    fn ready(self, events: EventSet, scope: &mut Scope<C, Self>)
         -> Async<Self, Option<Self>>;
 
     /// Gives socket a chance to register in event loop
-    fn register(self, reg: &mut Registrator) -> Async<Self, ()>;
+    fn register(self, reg: &mut dyn Registrator) -> Async<Self, ()>;
+
+    /// Called after each state transition, so machine can change interest
+    /// in the events or deregister its file descriptors
+    ///
+    /// The calls are compared with the ones made by the previous
+    /// transition in order, and the descriptor is reregistered only if the
+    /// interest has changed. If any call to the registrator fails the state
+    /// machine is stopped with `Error::Register`.
+    fn reregister(&mut self, _reg: &mut dyn Registrator) {}
 
     /// Timeout happened
-    fn timeout(self, context: &mut C) -> Async<Self, Option<Self>>;
+    ///
+    /// The `timer` is either the deadline of the current state or the one
+    /// set by `Scope::set_timer`.
+    fn timeout(self, timer: Timer, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>;
 
     /// Message received
-    fn wakeup(self, context: &mut C) -> Async<Self, Option<Self>>;
+    fn wakeup(self, scope: &mut Scope<C, Self>) -> Async<Self, Option<Self>>;
+
+    /// The machine is removed from the loop to be added to another one
+    ///
+    /// Deregister all the file descriptors here, otherwise the old loop
+    /// keeps polling them. `register` is called again in the new loop.
+    fn detach(&mut self, _reg: &mut dyn Registrator) {}
+
+    /// The loop is shutting down gracefully
+    ///
+    /// The machine should finish the work in progress and stop, it's
+    /// dropped anyway when the deadline of the shutdown passes. Default is
+    /// to stop immediately.
+    fn shutdown(self, _scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        Async::Stop
+    }
+
+    /// The child state machine returned by previous action can't be added
+    /// to the main loop
+    ///
+    /// The child is handed back so it can be shut down in the way specific
+    /// to the protocol. By default it's just dropped.
+    fn spawn_failed(self, _child: Self, _reason: Abort,
+        _scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        Async::Continue(self, None)
+    }
 }
 
 impl<C, M> Handler<C, M>
     where M: EventMachine<C>,
 {
+    /// Creates handler with default configuration
+    ///
+    /// Note the event loop is created with it's own configuration, so
+    /// consider using `HandlerConfig::event_loop` and `Handler::configured`
     pub fn new(context: C, _eloop: &mut EventLoop<Handler<C, M>>)
         -> Handler<C, M>
     {
-        // TODO(tailhook) create default config from the ulimit data instead
-        // of using real defaults
+        Handler::configured(context, &HandlerConfig::new())
+    }
+
+    pub fn configured(context: C, config: &HandlerConfig) -> Handler<C, M> {
+        Handler::with_clock(context, config, SystemClock)
+    }
+
+    /// Creates handler which takes the time from the `clock`
+    ///
+    /// This is mostly useful for testing with `clock::ManualClock`.
+    pub fn with_clock<K>(context: C, config: &HandlerConfig, clock: K)
+        -> Handler<C, M>
+        where K: Clock + 'static
+    {
+        let (inbox_sender, inbox) = mpsc::channel();
         Handler {
-            slab: Slab::new(4096),
-            context: context,
+            slab: Slab::new(config.slab_capacity),
+            generation: 0,
+            io_budget: config.io_budget,
+            errors: 0,
+            max_events: max(config.max_events_per_tick, 1),
+            events: 0,
+            deferred: VecDeque::new(),
+            pending: HashMap::new(),
+            wheel: Wheel::new(config.timer_wheel_size, clock.now()),
+            wakeup: None,
+            clock: Box::new(clock),
+            shutdown: None,
+            summary: None,
+            inbox,
+            inbox_sender,
+            on_detach: None,
+            context,
+        }
+    }
+
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut C {
+        &mut self.context
+    }
+
+    /// Returns notifier which wakes up the machine `token`
+    ///
+    /// Same as `Scope::notifier` of the machine, but can be obtained from
+    /// outside. Returns `None` if there is no such machine.
+    pub fn notifier(&self, eloop: &dyn LoopApi, token: Token)
+        -> Option<Notifier>
+    {
+        self.slab.get(token)
+        .map(|&Cell(_, gen, ..)| Notifier::new(token, gen, eloop.channel()))
+    }
+
+    /// Number of state machines stopped with `Async::Error` so far
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Whether graceful shutdown is in progress
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_some()
+    }
+
+    /// Summary of the graceful shutdown, when it's complete
+    pub fn shutdown_summary(&self) -> Option<ShutdownSummary> {
+        self.summary
+    }
+
+    /// Returns the mailbox which adds machines to this loop
+    ///
+    /// Machines are moved between loops by `Handler::detach` or
+    /// `Notifier::detach` in the old loop and `Mailbox::send` to the new
+    /// one. The mailbox may be sent to other threads if `M: Send`.
+    pub fn mailbox(&self, eloop: &dyn LoopApi) -> Mailbox<M> {
+        Mailbox::new(self.inbox_sender.clone(), eloop.channel())
+    }
+
+    /// Sets the function receiving machines detached by `Notifier::detach`
+    ///
+    /// It usually sends the machine to the `Mailbox` of another loop. By
+    /// default detached machines are dropped.
+    pub fn on_detach<F>(&mut self, f: F)
+        where F: FnMut(M, &mut C) + Send + 'static
+    {
+        self.on_detach = Some(Box::new(f));
+    }
+}
+
+/// Calls `EventMachine::reregister` of the machine which is still alive
+///
+/// If registrator fails the machine is stopped with `Error::Register`. The
+/// result of the action (i.e. the new machine) is returned separately in
+/// this case, so it's still added to the loop.
+fn reregister<M, C, R>(ares: Async<M, R>, mut reg: Reg)
+    -> (Async<M, R>, Option<R>, Interest)
+    where M: EventMachine<C>
+{
+    let ares = match ares {
+        Async::Continue(mut m, result) => {
+            m.reregister(&mut reg);
+            Async::Continue(m, result)
+        }
+        Async::Timeout(mut m, deadline) => {
+            m.reregister(&mut reg);
+            Async::Timeout(m, deadline)
         }
+        ares => ares,
+    };
+    let (failed, interest) = reg.finish();
+    if !failed {
+        return (ares, None, interest);
     }
+    let result = match ares {
+        Async::Continue(_, result) => Some(result),
+        _ => None,
+    };
+    let err = Error::Register(Abort::RegisterFailed);
+    (Async::Error(err), result, interest)
 }
 
-fn replacement<M, C, R>(ares: Async<M, R>, eloop: &mut EventLoop<Handler<C, M>>,
-    token: Token, old_timer: Option<(SteadyTime, mio::Timeout)>)
-    -> (Option<Cell<M>>, Option<R>)
-    where M:Sized, M:EventMachine<C>, R:Sized
+/// Logs and counts the failure of the state machine
+///
+/// The `Async::Error` is replaced with `Async::Stop`.
+fn stop_on_error<M, R>(ares: Async<M, R>, token: Token, errors: &mut u64)
+    -> Async<M, R>
 {
-    use async::Async::*;
     match ares {
-        Continue(m, result) => {
-            if let Some((_, ticket)) = old_timer {
-                eloop.clear_timeout(ticket);
-            }
-            (Some(Cell(m, None)), Some(result))
-        }
-        Stop => (None, None),
-        Timeout(m, deadline) => {
-            let ticket = match old_timer {
-                Some((dl, t)) if dl == deadline => Some(t),
-                Some((_, ticket)) => {
-                    eloop.clear_timeout(ticket);
-                    None
-                }
-                None => None,
-            };
-            let ticket = ticket.unwrap_or_else(|| {
-                let left = deadline - SteadyTime::now();
-                eloop.timeout_ms(
-                        Timeo::Fsm(token),
-                        max(left.num_milliseconds(), 0) as u64,
-                    ).ok().expect("No more timer slots?")
-            });
-            (Some(Cell(m, Some((deadline, ticket)))), None)
+        Async::Error(e) => {
+            warn!("State machine {:?} failed: {}", token, e);
+            *errors += 1;
+            Async::Stop
         }
+        ares => ares,
     }
 }
 
-impl<'a, Ctx, M> Handler<Ctx, M>
+/// Builds new cell for the state machine
+fn replacement<M, R>(ares: Async<M, R>,
+    wheel: &mut Wheel, token: Token, generation: u64,
+    mut timers: Timers, interest: Interest)
+    -> (Option<Cell<M>>, Option<R>)
+{
+    use async::Async::*;
+    let (m, result, deadline) = match ares {
+        Continue(m, result) => (m, Some(result), None),
+        Timeout(m, dl) => (m, None, Some(dl)),
+        Stop | Error(_) => {
+            timers.cancel(wheel);
+            return (None, None);
+        }
+    };
+    timers.transition(deadline);
+    timers.schedule(wheel, token, generation);
+    (Some(Cell(m, generation, timers, interest)), result)
+}
+
+impl<Ctx, M> Handler<Ctx, M>
     where M: EventMachine<Ctx>
 {
-    pub fn add_root(&mut self, eloop: &mut EventLoop<Self>, m: M) {
-        match self.slab.insert(Cell(m, None)) {
-            Ok(tok) => {
-                self.slab.replace_with(tok, |Cell(m, timer)| {
-                    let mach = m.register(&mut Reg {
-                        eloop: eloop,
-                        token: tok
-                        });
-                    replacement(mach, eloop, tok, timer).0
-                }).unwrap(); // just inserted so must work
+    pub fn add_root(&mut self, eloop: &mut EventLoop<Self>, m: M)
+        -> Result<Token, Abort>
+    {
+        let result = self.add_machine(eloop, m);
+        self.schedule_wakeup(eloop);
+        result
+    }
+
+    /// Runs the loop until it's shut down
+    ///
+    /// Returns the summary if the loop was stopped by the graceful shutdown
+    /// rather than by `Scope::shutdown_loop`.
+    pub fn run(&mut self, eloop: &mut EventLoop<Self>)
+        -> io::Result<Option<ShutdownSummary>>
+    {
+        eloop.run(self)?;
+        Ok(self.summary)
+    }
+
+    /// Starts graceful shutdown of the loop
+    ///
+    /// Listeners stop accepting connections and `EventMachine::shutdown` is
+    /// called for every state machine. The loop stops when all machines
+    /// are finished or at the `deadline`, dropping the remaining ones. Use
+    /// `Scope::shutdown_gracefully` or `Notifier::shutdown_gracefully`
+    /// from inside the loop or from other threads.
+    pub fn shutdown_gracefully(&mut self, eloop: &mut EventLoop<Self>,
+        deadline: SteadyTime)
+    {
+        self.begin_shutdown(eloop, deadline);
+        self.check_shutdown(eloop);
+        self.schedule_wakeup(eloop);
+    }
+
+    pub(crate) fn add_machine(&mut self, eloop: &mut dyn LoopApi, m: M)
+        -> Result<Token, Abort>
+    {
+        let tok = self.insert(eloop, m).map_err(|(reason, _)| reason)?;
+        if self.shutdown.is_some() {
+            self.shutdown_machine(tok, eloop);
+        }
+        Ok(tok)
+    }
+
+    /// Removes the machine from the loop
+    ///
+    /// Timers of the machine are cancelled and `EventMachine::detach` is
+    /// called to deregister its file descriptors. Use `Mailbox::send` to
+    /// add the machine to another loop.
+    pub fn detach(&mut self, eloop: &mut EventLoop<Self>, token: Token)
+        -> Option<M>
+    {
+        let m = self.detach_machine(eloop, token);
+        self.schedule_wakeup(eloop);
+        m
+    }
+
+    pub(crate) fn detach_machine(&mut self, eloop: &mut dyn LoopApi,
+        token: Token)
+        -> Option<M>
+    {
+        let Cell(mut m, generation, mut timers, interest) =
+            self.slab.remove(token)?;
+        timers.cancel(&mut self.wheel);
+        if let Some(ref mut shutdown) = self.shutdown {
+            shutdown.detached += 1;
+        }
+        let now = self.clock.now();
+        let mut reg = Reg::new(eloop, token, generation, now, interest);
+        // Errors are logged, the descriptor is closed with the machine anyway
+        m.detach(&mut reg);
+        Some(m)
+    }
+
+    /// Adds the machines received by the `Mailbox`
+    fn receive_machines(&mut self, eloop: &mut dyn LoopApi) {
+        while let Ok(m) = self.inbox.try_recv() {
+            if let Err(reason) = self.add_machine(eloop, m) {
+                error!("Can't add the machine moved to the loop: {:?}",
+                    reason);
             }
-            Err(_) => {
-                unimplemented!();
+        }
+    }
+
+    fn shutdown_machine(&mut self, token: Token, eloop: &mut dyn LoopApi) {
+        self.action_loop(token, eloop, M::shutdown);
+    }
+
+    /// Inserts and registers the state machine
+    ///
+    /// On failure returns the machine back if it's still alive
+    pub(crate) fn insert(&mut self, eloop: &mut dyn LoopApi, m: M)
+        -> Result<Token, (Abort, Option<M>)>
+    {
+        self.generation = self.generation.wrapping_add(1);
+        let generation = self.generation;
+        let cell = Cell(m, generation, Timers::new(), Interest::new());
+        let tok = match self.slab.insert(cell) {
+            Ok(tok) => tok,
+            Err(Cell(m, ..)) => return Err((Abort::NoSlabSpace, Some(m))),
+        };
+        let mut result = Ok(tok);
+        let errors = &mut self.errors;
+        let wheel = &mut self.wheel;
+        let now = self.clock.now();
+        self.slab.replace_with(tok, |Cell(m, gen, timers, interest)| {
+            let mut reg = Reg::new(eloop, tok, gen, now, interest);
+            let mach = stop_on_error(m.register(&mut reg), tok, errors);
+            let (failed, interest) = reg.finish();
+            let cell = replacement(mach, wheel, tok, gen, timers, interest).0;
+            match cell {
+                Some(Cell(m, _, mut timers, _)) if failed => {
+                    timers.cancel(wheel);
+                    result = Err((Abort::RegisterFailed, Some(m)));
+                    None
+                }
+                None if failed => {
+                    result = Err((Abort::RegisterFailed, None));
+                    None
+                }
+                None => {
+                    result = Err((Abort::MachineAddError, None));
+                    None
+                }
+                cell => cell,
             }
+        }).unwrap(); // just inserted so must work
+        if let (Ok(_), Some(ref mut shutdown)) = (&result, &mut self.shutdown) {
+            shutdown.total += 1;
         }
+        result
     }
 
-    fn action_loop<'x, F>(&mut self, token: Token,
-        eloop: &'x mut EventLoop<Self>, fun: F)
-        where F: Fn(M, &mut Ctx) -> Async<M, Option<M>>,
+    fn action_loop<F>(&mut self, token: Token,
+        eloop: &mut dyn LoopApi, fun: F)
+        where F: Fn(M, &mut Scope<Ctx, M>) -> Async<M, Option<M>>,
     {
-        let ref mut ctx = self.context;
+        let mut failed = Vec::new();
         loop {
             let mut new_machine = None;
-            self.slab.replace_with(token, |Cell(m, timer)| {
-                let mach = fun(m, ctx);
-                let (cell, res) = replacement(mach, eloop, token, timer);
-                new_machine = res.and_then(|r| r);
+            let mut alive = false;
+            let mut spawned = Vec::new();
+            let failure = failed.pop();
+            let now = self.clock.now();
+            let ctx = &mut self.context;
+            let io_budget = self.io_budget;
+            let errors = &mut self.errors;
+            let wheel = &mut self.wheel;
+            self.slab.replace_with(token,
+                |Cell(m, gen, mut timers, interest)|
+            {
+                let mach = {
+                    let mut scope = Scope::new(token, gen, now, io_budget,
+                        ctx, &mut *eloop, &mut spawned, &mut timers);
+                    match failure {
+                        Some((child, reason)) => {
+                            m.spawn_failed(child, reason, &mut scope)
+                        }
+                        None => fun(m, &mut scope),
+                    }
+                };
+                let reg = Reg::new(eloop, token, gen, now, interest);
+                let (mach, orphan, interest) = reregister(mach, reg);
+                let mach = stop_on_error(mach, token, errors);
+                let (cell, res) = replacement(mach,
+                    wheel, token, gen, timers, interest);
+                alive = cell.is_some();
+                new_machine = res.or(orphan).and_then(|r| r);
                 cell
             }).ok();  // Spurious events are ok in mio
-            if let Some(new) = new_machine {
-                match self.slab.insert(Cell(new, None)) {
-                    Ok(tok) => {
-                        self.slab.replace_with(tok, |Cell(m, timer)| {
-                            let mach = m.register(&mut Reg {
-                                eloop: eloop,
-                                token: tok
-                                });
-                            replacement(mach, eloop, tok, timer).0
-                        }).unwrap(); // just inserted so must work
+            // The slot of the failed machine may be reused by the new one
+            let again = alive && new_machine.is_some();
+            for new in new_machine.into_iter().chain(spawned) {
+                match self.insert(eloop, new) {
+                    Ok(tok) if self.shutdown.is_some() => {
+                        self.shutdown_machine(tok, eloop);
+                    }
+                    Ok(_) | Err((_, None)) => {}
+                    Err((reason, Some(child))) if alive => {
+                        failed.push((child, reason));
                     }
-                    Err(_) => {
-                        unimplemented!();
+                    Err((reason, Some(_))) => {
+                        error!("Can't add the child of stopped {:?}: {:?}",
+                            token, reason);
                     }
                 }
-            } else {
+            }
+            if !again && failed.is_empty() {
                 break;
             }
         }
     }
+
+    /// Calls `timeout` of the state machines whose timers expired
+    ///
+    /// This is done on each iteration of the loop. Call it to run timers
+    /// right after advancing `clock::ManualClock` in tests.
+    pub fn fire_timers(&mut self, eloop: &mut EventLoop<Self>) {
+        self.expire_timers(eloop);
+        self.check_shutdown(eloop);
+        self.schedule_wakeup(eloop);
+    }
+
+    /// Delivers readiness of the file descriptor registered by `token`
+    pub(crate) fn io_ready(&mut self, eloop: &mut dyn LoopApi,
+        token: Token, events: EventSet)
+    {
+        self.action_loop(token, eloop, |m, scope| m.ready(events, scope));
+    }
+
+    /// Keeps the io event for the next loop iteration
+    ///
+    /// Events of the same token are merged, so level-triggered descriptors
+    /// don't grow the queue while waiting.
+    fn defer(&mut self, token: Token, events: EventSet) {
+        let generation = match self.slab.get(token) {
+            Some(&Cell(_, gen, ..)) => gen,
+            None => return,  // Spurious events are ok in mio
+        };
+        match self.pending.entry(token) {
+            Entry::Occupied(mut e) => {
+                let &mut (ref mut gen, ref mut set) = e.get_mut();
+                if *gen == generation {
+                    *set = *set | events;
+                } else {
+                    *gen = generation;
+                    *set = events;
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert((generation, events));
+                self.deferred.push_back(token);
+            }
+        }
+    }
+
+    /// Processes deferred io events until the limit of the iteration
+    fn process_deferred(&mut self, eloop: &mut dyn LoopApi) {
+        while self.events < self.max_events {
+            let token = match self.deferred.pop_front() {
+                Some(token) => token,
+                None => break,
+            };
+            let (generation, events) = self.pending.remove(&token)
+                .expect("every deferred token is pending");
+            self.events += 1;
+            if self.is_current(token, generation) {
+                self.io_ready(eloop, token, events);
+            }
+        }
+    }
+
+    pub(crate) fn notified(&mut self, eloop: &mut dyn LoopApi, msg: Notify) {
+        match msg {
+            Notify::Fsm(token, generation) => {
+                // The machine which created the notifier may be replaced
+                if !self.is_current(token, generation) {
+                    return;
+                }
+                self.action_loop(token, eloop, |m, scope| m.wakeup(scope));
+            }
+            Notify::Ready(token, generation) => {
+                if !self.is_current(token, generation) {
+                    return;
+                }
+                self.action_loop(token, eloop,
+                    |m, scope| m.ready(EventSet::none(), scope));
+            }
+            Notify::Shutdown(deadline) => {
+                self.begin_shutdown(eloop, deadline);
+            }
+            Notify::Detach(token, generation) => {
+                if !self.is_current(token, generation) {
+                    return;
+                }
+                if let Some(m) = self.detach_machine(eloop, token) {
+                    match self.on_detach {
+                        Some(ref mut f) => f(m, &mut self.context),
+                        None => warn!("Dropping detached machine {:?}", token),
+                    }
+                }
+            }
+            Notify::Inbox => self.receive_machines(eloop),
+            Notify::Deferred => self.process_deferred(eloop),
+        }
+    }
+
+    /// Whether the machine which sent the notification is still in the slot
+    ///
+    /// The slot may be reused by another machine after the notifier of the
+    /// stopped one was called.
+    fn is_current(&self, token: Token, generation: u64) -> bool {
+        match self.slab.get(token) {
+            Some(&Cell(_, gen, ..)) => gen == generation,
+            None => false,
+        }
+    }
+
+    /// The time when `expire_timers` or `check_shutdown` are needed next
+    pub(crate) fn next_timer(&self) -> Option<SteadyTime> {
+        let shutdown = self.shutdown.as_ref().map(|s| s.deadline);
+        match (self.wheel.next_wakeup(), shutdown) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn begin_shutdown(&mut self, eloop: &mut dyn LoopApi,
+        deadline: SteadyTime)
+    {
+        if let Some(ref mut shutdown) = self.shutdown {
+            // Repeated request may only make the deadline earlier
+            shutdown.deadline = min(shutdown.deadline, deadline);
+            return;
+        }
+        if self.summary.is_some() {
+            return;
+        }
+        self.shutdown = Some(Shutdown {
+            started: self.clock.now(),
+            deadline,
+            total: self.slab.count(),
+            detached: 0,
+        });
+        let capacity = self.slab.count() + self.slab.remaining();
+        let tokens: Vec<_> = (0..capacity).map(Token)
+            .filter(|&tok| self.slab.contains(tok))
+            .collect();
+        for tok in tokens {
+            self.shutdown_machine(tok, eloop);
+        }
+    }
+
+    /// Stops the loop when all machines are finished or at the deadline
+    pub(crate) fn check_shutdown(&mut self, eloop: &mut dyn LoopApi) {
+        let now = self.clock.now();
+        match self.shutdown {
+            Some(ref s) if self.slab.is_empty() || s.deadline <= now => {}
+            _ => return,
+        }
+        let shutdown = self.shutdown.take().unwrap();
+        let dropped = self.slab.count();
+        if dropped > 0 {
+            warn!("Dropping {} state machines at shutdown deadline",
+                dropped);
+        }
+        for &mut Cell(_, _, ref mut timers, _) in self.slab.iter_mut() {
+            timers.cancel(&mut self.wheel);
+        }
+        self.slab.clear();
+        self.summary = Some(ShutdownSummary {
+            finished: shutdown.total - shutdown.detached - dropped,
+            dropped,
+            detached: shutdown.detached,
+            elapsed: now - shutdown.started,
+        });
+        eloop.shutdown();
+    }
+
+    pub(crate) fn expire_timers(&mut self, eloop: &mut dyn LoopApi) {
+        let now = self.clock.now();
+        if self.wheel.next_wakeup().map_or(true, |x| x > now) {
+            return;
+        }
+        let mut expired = Vec::new();
+        self.wheel.expire(now, &mut expired);
+        for (key, token, generation) in expired {
+            let fired = match self.slab.get_mut(token) {
+                Some(&mut Cell(_, gen, ref mut timers, _))
+                if gen == generation => timers.fired(key, now),
+                _ => None,
+            };
+            if let Some(timer) = fired {
+                self.action_loop(token, eloop,
+                    |m, scope| m.timeout(timer, scope));
+            }
+        }
+    }
+
+    /// Makes sure the loop wakes up when the earliest timer expires
+    fn schedule_wakeup(&mut self, eloop: &mut EventLoop<Self>) {
+        let next = self.next_timer();
+        if self.wakeup.as_ref().map(|&(dl, _)| dl) == next {
+            return;
+        }
+        if let Some((_, ticket)) = self.wakeup.take() {
+            eloop.clear_timeout(ticket);
+        }
+        if let Some(deadline) = next {
+            let left = (deadline - self.clock.now()).num_milliseconds();
+            match eloop.timeout_ms(Timeo::Wheel, max(left, 0) as u64) {
+                Ok(ticket) => self.wakeup = Some((deadline, ticket)),
+                // Retried on the next iteration of the loop
+                Err(e) => error!("Can't schedule timer wakeup: {:?}", e),
+            }
+        }
+    }
 }
 
 impl<Ctx, M> mio::Handler for Handler<Ctx, M>
@@ -177,27 +895,490 @@ impl<Ctx, M> mio::Handler for Handler<Ctx, M>
 {
     type Message = Notify;
     type Timeout = Timeo;
-    fn ready<'x>(&mut self, eloop: &'x mut EventLoop<Self>,
+    fn ready(&mut self, eloop: &mut EventLoop<Self>,
         token: Token, events: EventSet)
     {
-        self.action_loop(token, eloop, |m, ctx| m.ready(events, ctx));
+        // Deferred events go first, so new ones wait in the queue too
+        if self.events >= self.max_events || !self.deferred.is_empty() {
+            self.defer(token, events);
+            return;
+        }
+        self.events += 1;
+        self.io_ready(eloop, token, events);
     }
 
     fn notify(&mut self, eloop: &mut EventLoop<Self>, msg: Notify) {
-        match msg {
-            Notify::Fsm(token) => {
-                self.action_loop(token, eloop, |m, ctx| m.wakeup(ctx));
+        self.notified(eloop, msg);
+    }
+
+    fn timeout(&mut self, _eloop: &mut EventLoop<Self>, timeo: Timeo) {
+        match timeo {
+            Timeo::Wheel => self.wakeup = None,
+        }
+    }
+
+    fn tick(&mut self, eloop: &mut EventLoop<Self>) {
+        // Picks up the machines whose notification didn't fit into queue
+        self.receive_machines(eloop);
+        // Timers set in this iteration are scheduled before the next poll
+        self.fire_timers(eloop);
+        self.events = 0;
+        if !self.deferred.is_empty() {
+            // Also makes the next poll non-blocking
+            if let Err(e) = eloop.channel().send(Notify::Deferred) {
+                warn!("Can't schedule deferred events: {:?}", e);
             }
         }
     }
+}
 
-    fn timeout(&mut self, eloop: &mut EventLoop<Self>, timeo: Timeo) {
-        match timeo {
-            Timeo::Fsm(token) => {
-                self.action_loop(token, eloop, |m, ctx| m.wakeup(ctx));
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::mpsc::{channel, Sender};
+
+    use time::{SteadyTime, Duration};
+    use mio::{EventLoop, EventSet, PollOpt, Evented, Token};
+    use mio::unix::{pipe, PipeReader};
+
+    use {Async, EventMachine, Notifier, HandlerConfig, Scope, Timer};
+    use clock::{Clock, ManualClock};
+    use notify::Channel;
+    use super::{Handler, Registrator, Abort, LoopApi, Notify};
+
+    #[derive(Default)]
+    struct Context {
+        timeouts: Vec<SteadyTime>,
+        timers: Vec<Timer>,
+        wakeups: usize,
+        readies: usize,
+        spawn_failures: Vec<Abort>,
+        shutdowns: usize,
+    }
+
+    enum Machine {
+        Sleep(SteadyTime, Sender<Notifier>),
+        Parent(Sender<Notifier>, fn() -> Machine),
+        Twice(PipeReader),
+        /// Stops listening for the input while paused
+        Reader(PipeReader, bool, Sender<Notifier>),
+        /// Sets keyed timers on the first wakeup
+        Keyed,
+        /// Stops listening for the input and spawns a child on wakeup
+        Fork(PipeReader, bool),
+        /// Keeps running after shutdown is requested
+        Linger,
+        Idle,
+    }
+
+    impl EventMachine<Context> for Machine {
+        fn ready(self, _events: EventSet, scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            scope.readies += 1;
+            Async::Continue(self, None)
+        }
+        fn register(self, reg: &mut dyn Registrator) -> Async<Self, ()> {
+            match self {
+                Machine::Sleep(deadline, tx) => {
+                    tx.send(reg.notifier()).unwrap();
+                    Async::Timeout(Machine::Sleep(deadline, tx), deadline)
+                }
+                Machine::Parent(tx, child) => {
+                    tx.send(reg.notifier()).unwrap();
+                    Async::Continue(Machine::Parent(tx, child), ())
+                }
+                Machine::Twice(pipe) => {
+                    // Second registration of the same fd fails
+                    reg.register(&pipe, EventSet::readable(), PollOpt::level())
+                        .unwrap();
+                    reg.register(&pipe, EventSet::readable(), PollOpt::level())
+                        .unwrap_err();
+                    Async::Continue(Machine::Twice(pipe), ())
+                }
+                Machine::Reader(pipe, paused, tx) => {
+                    tx.send(reg.notifier()).unwrap();
+                    reg.register(&pipe, EventSet::readable(), PollOpt::level())
+                        .unwrap();
+                    Async::Continue(Machine::Reader(pipe, paused, tx), ())
+                }
+                Machine::Keyed => Async::Continue(Machine::Keyed, ()),
+                Machine::Linger => Async::Continue(Machine::Linger, ()),
+                Machine::Fork(pipe, forked) => {
+                    reg.register(&pipe, EventSet::readable(), PollOpt::level())
+                        .unwrap();
+                    Async::Continue(Machine::Fork(pipe, forked), ())
+                }
+                Machine::Idle => Async::Continue(Machine::Idle, ()),
             }
         }
+        fn detach(&mut self, reg: &mut dyn Registrator) {
+            if let Machine::Reader(ref pipe, _, _) = *self {
+                reg.deregister(pipe).unwrap();
+            }
+        }
+        fn reregister(&mut self, reg: &mut dyn Registrator) {
+            if let Machine::Reader(ref pipe, paused, _) = *self {
+                let interest = if paused {
+                    EventSet::none()
+                } else {
+                    EventSet::readable()
+                };
+                reg.reregister(pipe, interest, PollOpt::level()).unwrap();
+            }
+            if let Machine::Fork(ref pipe, true) = *self {
+                // The failure is handled by the loop
+                reg.reregister(pipe, EventSet::none(), PollOpt::level()).ok();
+            }
+        }
+        fn timeout(self, timer: Timer, scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            scope.timeouts.push(SteadyTime::now());
+            scope.timers.push(timer);
+            Async::Continue(self, None)
+        }
+        fn wakeup(self, scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            scope.wakeups += 1;
+            match self {
+                Machine::Parent(tx, child) => {
+                    Async::Continue(Machine::Parent(tx, child), Some(child()))
+                }
+                Machine::Reader(pipe, paused, tx) => {
+                    Async::Continue(Machine::Reader(pipe, !paused, tx), None)
+                }
+                Machine::Fork(pipe, _) => {
+                    Async::Continue(Machine::Fork(pipe, true),
+                        Some(Machine::Idle))
+                }
+                Machine::Keyed => {
+                    if scope.timer(1).is_none() {
+                        let now = scope.now();
+                        scope.set_timer(2, now + Duration::milliseconds(100));
+                        scope.set_timer(1, now + Duration::milliseconds(50));
+                        scope.set_timer(3, now + Duration::milliseconds(10));
+                        assert!(scope.clear_timer(3).is_some());
+                    }
+                    Async::Continue(Machine::Keyed, None)
+                }
+                _ => Async::Stop,
+            }
+        }
+        fn shutdown(self, scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            scope.shutdowns += 1;
+            match self {
+                Machine::Linger => Async::Continue(Machine::Linger, None),
+                _ => Async::Stop,
+            }
+        }
+        fn spawn_failed(self, _child: Self, reason: Abort,
+            scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            scope.spawn_failures.push(reason);
+            Async::Continue(self, None)
+        }
+    }
 
+    fn run_until(eloop: &mut EventLoop<Handler<Context, Machine>>,
+        handler: &mut Handler<Context, Machine>, deadline: SteadyTime)
+    {
+        while SteadyTime::now() < deadline {
+            eloop.run_once(handler).unwrap();
+        }
+    }
+
+    #[test]
+    fn timeout_called_once() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let deadline = SteadyTime::now() + Duration::milliseconds(150);
+        let (tx, _rx) = channel();
+        handler.add_root(&mut eloop, Machine::Sleep(deadline, tx)).unwrap();
+        run_until(&mut eloop, &mut handler,
+            deadline + Duration::milliseconds(500));
+        assert_eq!(handler.context.timeouts.len(), 1);
+        assert!(handler.context.timeouts[0] >= deadline);
+    }
+
+    #[test]
+    fn stopped_machine_timer_ignored() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let deadline = SteadyTime::now() + Duration::milliseconds(150);
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop, Machine::Sleep(deadline, tx)).unwrap();
+        rx.recv().unwrap().wakeup().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(handler.context.wakeups, 1);
+        // Reuses the slab slot of the stopped machine
+        handler.add_root(&mut eloop, Machine::Idle).unwrap();
+        run_until(&mut eloop, &mut handler,
+            deadline + Duration::milliseconds(500));
+        assert_eq!(handler.context.timeouts.len(), 0);
+    }
+
+    #[test]
+    fn manual_clock() {
+        let mut eloop = EventLoop::new().unwrap();
+        let clock = ManualClock::new();
+        let mut handler = Handler::with_clock(Context::default(),
+            &HandlerConfig::new(), clock.clone());
+        let deadline = clock.now() + Duration::seconds(60);
+        let (tx, _rx) = channel();
+        handler.add_root(&mut eloop, Machine::Sleep(deadline, tx)).unwrap();
+        clock.advance(Duration::seconds(59));
+        handler.fire_timers(&mut eloop);
+        assert_eq!(handler.context.timeouts.len(), 0);
+        clock.advance(Duration::seconds(1));
+        handler.fire_timers(&mut eloop);
+        assert_eq!(handler.context.timers, vec![Timer::Deadline]);
+    }
+
+    #[test]
+    fn keyed_timers() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let tok = handler.add_root(&mut eloop, Machine::Keyed).unwrap();
+        // Timers are kept when machine continues after the second wakeup
+        let notifier = handler.notifier(&eloop, tok).unwrap();
+        notifier.wakeup().unwrap();
+        notifier.wakeup().unwrap();
+        run_until(&mut eloop, &mut handler,
+            SteadyTime::now() + Duration::milliseconds(300));
+        assert_eq!(handler.context.wakeups, 2);
+        assert_eq!(handler.context.timers, vec![Timer::Id(1), Timer::Id(2)]);
     }
-}
 
+    #[test]
+    fn graceful_shutdown() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        handler.add_root(&mut eloop, Machine::Idle).unwrap();
+        handler.add_root(&mut eloop, Machine::Linger).unwrap();
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop,
+            Machine::Parent(tx, || Machine::Idle)).unwrap();
+        let notifier = rx.recv().unwrap();
+        let deadline = SteadyTime::now() + Duration::milliseconds(100);
+        notifier.shutdown_gracefully(deadline).unwrap();
+        let summary = handler.run(&mut eloop).unwrap().unwrap();
+        assert!(SteadyTime::now() >= deadline);
+        assert_eq!(handler.context.shutdowns, 3);
+        assert_eq!(summary.finished, 2);
+        assert_eq!(summary.dropped, 1);
+        assert_eq!(summary.detached, 0);
+        assert!(summary.elapsed >= Duration::milliseconds(99));
+        assert_eq!(handler.slab.count(), 0);
+        assert!(!handler.is_shutting_down());
+    }
+
+    #[test]
+    fn detached_at_shutdown() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        handler.add_root(&mut eloop, Machine::Idle).unwrap();
+        let tok = handler.add_root(&mut eloop, Machine::Linger).unwrap();
+        let deadline = SteadyTime::now() + Duration::seconds(5);
+        handler.shutdown_gracefully(&mut eloop, deadline);
+        assert!(handler.detach(&mut eloop, tok).is_some());
+        let summary = handler.run(&mut eloop).unwrap().unwrap();
+        assert_eq!(summary.finished, 1);
+        assert_eq!(summary.dropped, 0);
+        assert_eq!(summary.detached, 1);
+        assert!(summary.elapsed < Duration::seconds(5));
+    }
+
+    #[test]
+    fn move_between_loops() {
+        let mut eloop_a = EventLoop::new().unwrap();
+        let mut handler_a = Handler::new(Context::default(), &mut eloop_a);
+        let mut eloop_b = EventLoop::new().unwrap();
+        let mut handler_b = Handler::new(Context::default(), &mut eloop_b);
+        let mailbox = handler_b.mailbox(&eloop_b);
+        handler_a.on_detach(move |m, _| mailbox.send(m).ok().unwrap());
+        let (rd, mut wr) = pipe().unwrap();
+        let (tx, rx) = channel();
+        handler_a.add_root(&mut eloop_a, Machine::Reader(rd, false, tx))
+            .unwrap();
+        rx.recv().unwrap().detach().unwrap();
+        eloop_a.run_once(&mut handler_a).unwrap();
+        assert_eq!(handler_a.slab.count(), 0);
+        eloop_b.run_once(&mut handler_b).unwrap();
+        assert_eq!(handler_b.slab.count(), 1);
+        rx.recv().unwrap();  // registered again in the new loop
+        wr.write_all(b"x").unwrap();
+        eloop_b.run_once(&mut handler_b).unwrap();
+        assert_eq!(handler_b.context.readies, 1);
+        assert_eq!(handler_a.context.readies, 0);
+    }
+
+    #[test]
+    fn add_root_no_slab_space() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::configured(Context::default(),
+            HandlerConfig::new().slab_capacity(2));
+        handler.add_root(&mut eloop, Machine::Idle).unwrap();
+        handler.add_root(&mut eloop, Machine::Idle).unwrap();
+        assert_eq!(handler.add_root(&mut eloop, Machine::Idle),
+            Err(Abort::NoSlabSpace));
+    }
+
+    #[test]
+    fn spawn_no_slab_space() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::configured(Context::default(),
+            HandlerConfig::new().slab_capacity(2));
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop,
+            Machine::Parent(tx, || Machine::Idle)).unwrap();
+        handler.add_root(&mut eloop, Machine::Idle).unwrap();
+        rx.recv().unwrap().wakeup().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(handler.context.wakeups, 1);
+        assert_eq!(handler.context.spawn_failures, vec![Abort::NoSlabSpace]);
+    }
+
+    #[test]
+    fn add_root_register_failed() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let (rd, _wr) = pipe().unwrap();
+        assert_eq!(handler.add_root(&mut eloop, Machine::Twice(rd)),
+            Err(Abort::RegisterFailed));
+        assert_eq!(handler.slab.count(), 0);
+    }
+
+    #[test]
+    fn spawn_register_failed() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop,
+            Machine::Parent(tx, || Machine::Twice(pipe().unwrap().0)))
+            .unwrap();
+        rx.recv().unwrap().wakeup().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(handler.context.spawn_failures,
+            vec![Abort::RegisterFailed]);
+        assert_eq!(handler.slab.count(), 1);
+    }
+
+    #[test]
+    fn max_events_per_tick() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::configured(Context::default(),
+            HandlerConfig::new().max_events_per_tick(1));
+        let mut pipes = Vec::new();
+        for _ in 0..3 {
+            let (rd, mut wr) = pipe().unwrap();
+            let (tx, rx) = channel();
+            handler.add_root(&mut eloop, Machine::Reader(rd, false, tx))
+                .unwrap();
+            wr.write_all(b"x").unwrap();
+            pipes.push((wr, rx));
+        }
+        for i in 1..5 {
+            eloop.run_once(&mut handler).unwrap();
+            assert_eq!(handler.context.readies, i);
+        }
+        // Events of level-triggered pipes are merged while waiting
+        assert_eq!(handler.deferred.len(), 2);
+    }
+
+    /// Counts reregistrations and fails them when asked
+    struct MockLoop {
+        reregisters: usize,
+        fail: bool,
+        sender: Sender<Notify>,
+    }
+
+    impl LoopApi for MockLoop {
+        fn register(&mut self, _io: &dyn Evented, _token: Token,
+            _interest: EventSet, _opt: PollOpt) -> io::Result<()>
+        {
+            Ok(())
+        }
+        fn reregister(&mut self, _io: &dyn Evented, _token: Token,
+            _interest: EventSet, _opt: PollOpt) -> io::Result<()>
+        {
+            if self.fail {
+                return Err(io::Error::new(io::ErrorKind::Other, "failed"));
+            }
+            self.reregisters += 1;
+            Ok(())
+        }
+        fn deregister(&mut self, _io: &dyn Evented) -> io::Result<()> {
+            Ok(())
+        }
+        fn channel(&self) -> Channel {
+            Channel::Queue(self.sender.clone())
+        }
+        fn shutdown(&mut self) {}
+    }
+
+    #[test]
+    fn reregister_changed_interest() {
+        let (sender, notifications) = channel();
+        let mut lp = MockLoop { reregisters: 0, fail: false, sender };
+        let mut handler = Handler::configured(Context::default(),
+            &HandlerConfig::new());
+        let (rd, _wr) = pipe().unwrap();
+        let (tx, rx) = channel();
+        let tok = handler.add_machine(&mut lp, Machine::Reader(rd, false, tx))
+            .unwrap();
+        let notifier = rx.recv().unwrap();
+        // Same interest as registered
+        handler.io_ready(&mut lp, tok, EventSet::readable());
+        assert_eq!(lp.reregisters, 0);
+        notifier.wakeup().unwrap();  // pause
+        handler.notified(&mut lp, notifications.try_recv().unwrap());
+        assert_eq!(lp.reregisters, 1);
+        handler.io_ready(&mut lp, tok, EventSet::none());
+        assert_eq!(lp.reregisters, 1);
+    }
+
+    #[test]
+    fn reregister_failed() {
+        let (sender, notifications) = channel();
+        let mut lp = MockLoop { reregisters: 0, fail: true, sender };
+        let mut handler = Handler::configured(Context::default(),
+            &HandlerConfig::new());
+        let (rd, _wr) = pipe().unwrap();
+        let tok = handler.add_machine(&mut lp, Machine::Fork(rd, false))
+            .unwrap();
+        handler.notifier(&lp, tok).unwrap().wakeup().unwrap();
+        handler.notified(&mut lp, notifications.try_recv().unwrap());
+        assert_eq!(handler.errors(), 1);
+        // The child is added even though the parent is stopped
+        assert_eq!(handler.slab.count(), 1);
+        assert_eq!(handler.context.wakeups, 1);
+    }
+
+    #[test]
+    fn pause_reading() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let (rd, mut wr) = pipe().unwrap();
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop, Machine::Reader(rd, false, tx)).unwrap();
+        let notifier = rx.recv().unwrap();
+        wr.write_all(b"x").unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(handler.context.readies, 1);
+        notifier.wakeup().unwrap();  // pause
+        eloop.run_once(&mut handler).unwrap();
+        let readies = handler.context.readies;
+        eloop.run_once(&mut handler).unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(handler.context.readies, readies);
+        notifier.wakeup().unwrap();  // resume
+        eloop.run_once(&mut handler).unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert!(handler.context.readies > readies);
+    }
+}
@@ -9,11 +9,26 @@ extern crate time;
 extern crate mio;
 #[macro_use] extern crate log;
 extern crate memchr;
+extern crate libc;
 
 #[macro_use] pub mod async;
 pub mod transports;
 pub mod handler;
+pub mod scope;
+pub mod config;
+pub mod notify;
 pub mod buffer_util;
+pub mod error;
+pub mod timer;
+pub mod clock;
+pub mod runtime;
+#[cfg(any(test, feature="testing"))] pub mod testing;
 
-pub use handler::{EventMachine, Handler};
+pub use handler::{EventMachine, Handler, ShutdownSummary};
+pub use scope::{Scope, Void};
+pub use config::HandlerConfig;
 pub use async::Async;
+pub use error::Error;
+pub use timer::Timer;
+pub use clock::Clock;
+pub use notify::{Notifier, WakeupError, Mailbox};
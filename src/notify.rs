@@ -0,0 +1,223 @@
+use std::io;
+use std::sync::mpsc;
+
+use time::SteadyTime;
+use mio::{Token, Sender, NotifyError};
+
+use handler::Notify;
+
+
+/// A handle that wakes up a single state machine from any thread
+///
+/// The notifier is obtained from `Registrator::notifier()` while the state
+/// machine registers itself. It can be cloned and sent to other threads
+/// (e.g. to a thread pool job), and each call of `wakeup()` results in
+/// `EventMachine::wakeup` being called for the machine in the main loop.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    token: Token,
+    generation: u64,
+    channel: Channel,
+}
+
+/// Where the notifications are delivered
+#[derive(Clone, Debug)]
+pub enum Channel {
+    /// Notification queue of the mio loop
+    Loop(Sender<Notify>),
+    /// Queue of the loop-free harness, see `testing`
+    Queue(mpsc::Sender<Notify>),
+}
+
+/// Adds state machines to the loop from other loops or threads
+///
+/// Returned by `Handler::mailbox`.
+pub struct Mailbox<M> {
+    machines: mpsc::Sender<M>,
+    channel: Channel,
+}
+
+#[derive(Debug)]
+pub enum WakeupError {
+    /// Main loop has been shut down, nobody will receive the notification
+    Closed,
+    /// The notification queue of the main loop is full, you may retry later
+    Full,
+    /// Error waking up the main loop (should never happen in practice)
+    Io(io::Error),
+}
+
+impl Notifier {
+    pub fn new(token: Token, generation: u64, channel: Channel)
+        -> Notifier
+    {
+        Notifier {
+            token,
+            generation,
+            channel,
+        }
+    }
+    /// Schedules `wakeup` of the state machine
+    pub fn wakeup(&self) -> Result<(), WakeupError> {
+        send(&self.channel, Notify::Fsm(self.token, self.generation))
+    }
+    /// Detaches the state machine from the loop
+    ///
+    /// The machine is passed to the function set by `Handler::on_detach`,
+    /// see `Handler::detach` for details.
+    pub fn detach(&self) -> Result<(), WakeupError> {
+        send(&self.channel, Notify::Detach(self.token, self.generation))
+    }
+    /// Starts graceful shutdown of the whole loop
+    ///
+    /// See `Handler::shutdown_gracefully`
+    pub fn shutdown_gracefully(&self, deadline: SteadyTime)
+        -> Result<(), WakeupError>
+    {
+        send(&self.channel, Notify::Shutdown(deadline))
+    }
+}
+
+impl<M> Mailbox<M> {
+    pub(crate) fn new(machines: mpsc::Sender<M>, channel: Channel)
+        -> Mailbox<M>
+    {
+        Mailbox {
+            machines,
+            channel,
+        }
+    }
+    /// Sends the machine to the loop, where it's registered again
+    ///
+    /// The machine is returned back if the loop is gone. When notification
+    /// queue is full the machine is added on the next iteration of the loop.
+    pub fn send(&self, machine: M) -> Result<(), M> {
+        self.machines.send(machine).map_err(|e| e.0)?;
+        if let Err(e) = send(&self.channel, Notify::Inbox) {
+            warn!("Can't wake up the loop for the moved machine: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+impl<M> Clone for Mailbox<M> {
+    fn clone(&self) -> Mailbox<M> {
+        Mailbox::new(self.machines.clone(), self.channel.clone())
+    }
+}
+
+pub(crate) fn send(channel: &Channel, msg: Notify)
+    -> Result<(), WakeupError>
+{
+    match *channel {
+        Channel::Loop(ref sender) => match sender.send(msg) {
+            Ok(()) => Ok(()),
+            Err(NotifyError::Closed(_)) => Err(WakeupError::Closed),
+            Err(NotifyError::Full(_)) => Err(WakeupError::Full),
+            Err(NotifyError::Io(e)) => Err(WakeupError::Io(e)),
+        },
+        Channel::Queue(ref sender) => {
+            sender.send(msg).map_err(|_| WakeupError::Closed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use time::{SteadyTime, Duration};
+    use mio::{EventLoop, EventSet};
+
+    use {Async, EventMachine, Handler, Scope, Timer};
+    use handler::Registrator;
+    use super::{Notifier, WakeupError};
+
+    type Counter = Arc<AtomicUsize>;
+
+    struct Waiter(Sender<Notifier>);
+
+    impl EventMachine<Counter> for Waiter {
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Counter, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            Async::Continue(self, None)
+        }
+        fn register(self, reg: &mut dyn Registrator) -> Async<Self, ()> {
+            self.0.send(reg.notifier()).unwrap();
+            Async::Continue(self, ())
+        }
+        fn timeout(self, _timer: Timer, _scope: &mut Scope<Counter, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            Async::Continue(self, None)
+        }
+        fn wakeup(self, scope: &mut Scope<Counter, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            scope.fetch_add(1, Ordering::SeqCst);
+            Async::Stop
+        }
+    }
+
+    #[test]
+    fn wakeup_from_thread() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(counter.clone(), &mut eloop);
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop, Waiter(tx)).unwrap();
+        let notifier = rx.recv().unwrap();
+        thread::spawn(move || notifier.wakeup().unwrap()).join().unwrap();
+        let deadline = SteadyTime::now() + Duration::seconds(5);
+        while counter.load(Ordering::SeqCst) == 0
+            && SteadyTime::now() < deadline
+        {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn stale_notifier_ignored() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(counter.clone(), &mut eloop);
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop, Waiter(tx.clone())).unwrap();
+        let old = rx.recv().unwrap();
+        old.wakeup().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        // Reuses the slab slot of the stopped machine
+        handler.add_root(&mut eloop, Waiter(tx)).unwrap();
+        let new = rx.recv().unwrap();
+        old.wakeup().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        // The new machine is not detached by the old notifier either
+        old.detach().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        new.wakeup().unwrap();
+        eloop.run_once(&mut handler).unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn wakeup_closed_loop() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(counter, &mut eloop);
+        let (tx, rx) = channel();
+        handler.add_root(&mut eloop, Waiter(tx)).unwrap();
+        let notifier = rx.recv().unwrap();
+        drop(eloop);
+        match notifier.wakeup() {
+            Err(WakeupError::Closed) => {}
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,555 @@
+//! Running a main loop in each of several threads
+//!
+//! Every thread has its own `EventLoop<Handler<C, M>>` and its own context.
+//! State machines stay in the loop they were added to, unless moved
+//! explicitly with `Notifier::detach` and the `Mailbox` of another loop
+//! (see `Handler::mailbox`). Connections are distributed between the loops
+//! in one of two ways:
+//!
+//! 1. Each loop listens on its own socket bound by
+//!    `transports::bind_reuse_port`, and the kernel balances connections
+//! 2. The acceptor thread hands accepted sockets to the loops in turn, see
+//!    `Runtime::start_handoff` and `Handoff`
+use std::io;
+use std::io::ErrorKind::Other;
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use time::SteadyTime;
+use mio::{self, EventLoop, Token, EventSet, PollOpt, Evented, Selector};
+use mio::TryAccept;
+
+use {Async, EventMachine, Handler, HandlerConfig, Scope, Timer, Notifier};
+use ShutdownSummary;
+use handler::{Notify, Registrator};
+
+/// Token value of the `Handoff` which is not registered yet
+const UNREGISTERED: usize = !0;
+
+
+/// Builder of the multi-threaded runtime
+#[derive(Clone, Debug)]
+pub struct Runtime {
+    threads: usize,
+    config: HandlerConfig,
+}
+
+/// The running loops, returned by `Runtime::start`
+pub struct Workers {
+    loops: Vec<Loop>,
+    acceptor: Option<Loop>,
+}
+
+struct Loop {
+    channel: mio::Sender<Notify>,
+    thread: thread::JoinHandle<io::Result<Option<ShutdownSummary>>>,
+}
+
+/// Queue of the sockets handed to the loop by the acceptor thread
+///
+/// Serve it with `transports::accept::Serve`: the acceptor wakes up the
+/// machine after sending each socket, and `Serve` accepts all the queued
+/// sockets on wakeup, so it's fine when some wakeups are lost.
+pub struct Handoff<T> {
+    sockets: mpsc::Receiver<T>,
+    token: Arc<AtomicUsize>,
+}
+
+struct HandoffSender<T> {
+    sockets: mpsc::Sender<T>,
+    notifier: Notifier,
+}
+
+/// Accepts connections and sends them to the loops in turn
+struct Acceptor<L: TryAccept> {
+    listener: L,
+    loops: Vec<HandoffSender<L::Output>>,
+    next: usize,
+}
+
+type Started<R> = io::Result<(mio::Sender<Notify>, R)>;
+
+impl Runtime {
+    /// Creates runtime with a thread per CPU
+    pub fn new() -> Runtime {
+        Runtime {
+            threads: thread::available_parallelism()
+                .map(|n| n.get()).unwrap_or(1),
+            config: HandlerConfig::new(),
+        }
+    }
+    /// Number of threads with the main loop
+    pub fn threads(&mut self, num: usize) -> &mut Self {
+        self.threads = num;
+        self
+    }
+    /// Configuration of each loop
+    pub fn config(&mut self, config: &HandlerConfig) -> &mut Self {
+        self.config = config.clone();
+        self
+    }
+    /// Starts the loops
+    ///
+    /// The `context` is called in each thread with the index of the thread
+    /// to create the context of the loop, then `setup` adds the root state
+    /// machines, e.g. `Serve` for the listener bound by
+    /// `transports::bind_reuse_port`. Returns when all the loops are set up.
+    pub fn start<C, M, F, S>(&self, context: F, setup: S)
+        -> io::Result<Workers>
+        where M: EventMachine<C> + 'static, C: 'static,
+              F: Fn(usize) -> C + Send + Sync + 'static,
+              S: Fn(usize, &mut EventLoop<Handler<C, M>>, &mut Handler<C, M>)
+                    -> io::Result<()> + Send + Sync + 'static,
+    {
+        let (loops, _) = self.spawn_loops(context, setup)?;
+        Ok(Workers { loops, acceptor: None })
+    }
+    /// Starts the loops and the acceptor thread for the `listener`
+    ///
+    /// Same as `start` but `setup` also receives the `Handoff` which it
+    /// must add to the loop, wrapped into `Serve`. The connections accepted
+    /// from the `listener` are handed to the loops in turn.
+    pub fn start_handoff<C, M, F, S, L>(&self, listener: L, context: F,
+        setup: S)
+        -> io::Result<Workers>
+        where M: EventMachine<C> + 'static, C: 'static,
+              L: TryAccept + Evented + Send + 'static,
+              L::Output: Send + 'static,
+              F: Fn(usize) -> C + Send + Sync + 'static,
+              S: Fn(usize, &mut EventLoop<Handler<C, M>>, &mut Handler<C, M>,
+                    Handoff<L::Output>)
+                    -> io::Result<()> + Send + Sync + 'static,
+    {
+        let (loops, senders) = self.spawn_loops(context,
+            move |idx, eloop, handler| {
+                let (sockets, handoff) = handoff();
+                let token = handoff.token.clone();
+                setup(idx, eloop, handler, handoff)?;
+                match token.load(Ordering::SeqCst) {
+                    UNREGISTERED => None,
+                    tok => handler.notifier(&*eloop, Token(tok)),
+                }
+                .map(|notifier| HandoffSender { sockets, notifier })
+                .ok_or_else(|| {
+                    io::Error::new(Other, "handoff is not registered")
+                })
+            })?;
+        let mut workers = Workers { loops, acceptor: None };
+        let acceptor = Acceptor { listener, loops: senders, next: 0 };
+        let (tx, rx) = mpsc::channel();
+        let config = self.config.clone();
+        let thread = thread::spawn(move || {
+            let started = config.event_loop().and_then(|mut eloop| {
+                let mut handler = Handler::configured((), &config);
+                handler.add_root(&mut eloop, acceptor)
+                    .map_err(|e| io::Error::new(Other,
+                        format!("can't add acceptor: {:?}", e)))?;
+                Ok((eloop, handler))
+            });
+            let (mut eloop, mut handler) = match started {
+                Ok(pair) => pair,
+                Err(e) => {
+                    tx.send(Err(e)).ok();
+                    return Ok(None);
+                }
+            };
+            tx.send(Ok(eloop.channel())).ok();
+            handler.run(&mut eloop)
+        });
+        match rx.recv() {
+            Ok(Ok(channel)) => {
+                workers.acceptor = Some(Loop { channel, thread });
+                Ok(workers)
+            }
+            Ok(Err(e)) => {
+                workers.stop();
+                Err(e)
+            }
+            Err(_) => {
+                workers.stop();
+                Err(io::Error::new(Other, "acceptor thread panicked"))
+            }
+        }
+    }
+    fn spawn_loops<C, M, F, S, R>(&self, context: F, setup: S)
+        -> io::Result<(Vec<Loop>, Vec<R>)>
+        where M: EventMachine<C> + 'static, C: 'static, R: Send + 'static,
+              F: Fn(usize) -> C + Send + Sync + 'static,
+              S: Fn(usize, &mut EventLoop<Handler<C, M>>, &mut Handler<C, M>)
+                    -> io::Result<R> + Send + Sync + 'static,
+    {
+        let context = Arc::new(context);
+        let setup = Arc::new(setup);
+        let mut started = Vec::new();
+        for idx in 0..self.threads {
+            let (tx, rx) = mpsc::channel::<Started<R>>();
+            let context = context.clone();
+            let setup = setup.clone();
+            let config = self.config.clone();
+            let thread = thread::spawn(move || {
+                let result = config.event_loop().and_then(|mut eloop| {
+                    let mut handler = Handler::configured(context(idx),
+                        &config);
+                    let value = setup(idx, &mut eloop, &mut handler)?;
+                    Ok((eloop, handler, value))
+                });
+                let (mut eloop, mut handler) = match result {
+                    Ok((eloop, handler, value)) => {
+                        tx.send(Ok((eloop.channel(), value))).ok();
+                        (eloop, handler)
+                    }
+                    Err(e) => {
+                        tx.send(Err(e)).ok();
+                        return Ok(None);
+                    }
+                };
+                handler.run(&mut eloop)
+            });
+            started.push((rx, thread));
+        }
+        let mut loops = Vec::new();
+        let mut values = Vec::new();
+        let mut error = None;
+        for (rx, thread) in started {
+            match rx.recv() {
+                Ok(Ok((channel, value))) => {
+                    loops.push(Loop { channel, thread });
+                    values.push(value);
+                }
+                Ok(Err(e)) => error = error.or(Some(e)),
+                Err(_) => {
+                    error = error.or(Some(
+                        io::Error::new(Other, "loop thread panicked")));
+                }
+            }
+        }
+        if let Some(e) = error {
+            Workers { loops, acceptor: None }.stop();
+            return Err(e);
+        }
+        Ok((loops, values))
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Runtime {
+        Runtime::new()
+    }
+}
+
+impl Workers {
+    /// Number of loops, not counting the acceptor
+    pub fn len(&self) -> usize {
+        self.loops.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.loops.is_empty()
+    }
+    /// Starts graceful shutdown of all the loops
+    ///
+    /// The acceptor stops immediately. See `Handler::shutdown_gracefully`.
+    pub fn shutdown_gracefully(&self, deadline: SteadyTime) {
+        let loops = self.acceptor.iter().chain(self.loops.iter());
+        for lp in loops {
+            if let Err(e) = lp.channel.send(Notify::Shutdown(deadline)) {
+                // Loop is already stopped if the channel is closed
+                warn!("Can't send shutdown to the loop: {:?}", e);
+            }
+        }
+    }
+    /// Waits for all the threads to stop
+    ///
+    /// Returns the shutdown summary of each loop, the first error of the
+    /// loop is returned instead if there was any.
+    pub fn join(self) -> io::Result<Vec<Option<ShutdownSummary>>> {
+        if let Some(acceptor) = self.acceptor {
+            join(acceptor.thread)?;
+        }
+        let mut summaries = Vec::new();
+        let mut error = None;
+        for lp in self.loops {
+            match join(lp.thread) {
+                Ok(summary) => summaries.push(summary),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(summaries),
+        }
+    }
+    /// Stops the loops immediately and waits for them
+    fn stop(self) {
+        self.shutdown_gracefully(SteadyTime::now());
+        self.join().ok();
+    }
+}
+
+fn join(thread: thread::JoinHandle<io::Result<Option<ShutdownSummary>>>)
+    -> io::Result<Option<ShutdownSummary>>
+{
+    thread.join()
+        .unwrap_or_else(|_| Err(io::Error::new(Other, "loop thread panicked")))
+}
+
+fn handoff<T>() -> (mpsc::Sender<T>, Handoff<T>) {
+    let (tx, rx) = mpsc::channel();
+    let token = Arc::new(AtomicUsize::new(UNREGISTERED));
+    (tx, Handoff { sockets: rx, token })
+}
+
+impl<T> HandoffSender<T> {
+    /// Returns the socket back if the loop is stopped
+    fn send(&self, sock: T) -> Result<(), T> {
+        self.sockets.send(sock).map_err(|e| e.0)?;
+        // The socket is picked up on the next wakeup if queue is full
+        if let Err(e) = self.notifier.wakeup() {
+            warn!("Can't wake up the loop: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+impl<T> TryAccept for Handoff<T> {
+    type Output = T;
+    fn accept(&self) -> io::Result<Option<T>> {
+        Ok(self.sockets.try_recv().ok())
+    }
+}
+
+/// Only remembers the token, to wake up the machine serving the handoff
+impl<T> Evented for Handoff<T> {
+    fn register(&self, _selector: &mut Selector, token: Token,
+        _interest: EventSet, _opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.token.store(token.as_usize(), Ordering::SeqCst);
+        Ok(())
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.register(selector, token, interest, opts)
+    }
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        self.token.store(UNREGISTERED, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl<L: TryAccept> Acceptor<L> {
+    fn dispatch(&mut self, mut sock: L::Output) {
+        let num = self.loops.len();
+        for i in 0..num {
+            let idx = (self.next + i) % num;
+            match self.loops[idx].send(sock) {
+                Ok(()) => {
+                    self.next = idx + 1;
+                    return;
+                }
+                Err(s) => sock = s,
+            }
+        }
+        warn!("All loops are stopped, dropping connection");
+    }
+}
+
+impl<C, L: TryAccept + Evented> EventMachine<C> for Acceptor<L> {
+    fn ready(mut self, _events: EventSet, _scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        loop {
+            match self.listener.accept() {
+                Ok(Some(sock)) => self.dispatch(sock),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                    break;
+                }
+            }
+        }
+        Async::Continue(self, None)
+    }
+    fn register(self, reg: &mut dyn Registrator) -> Async<Self, ()> {
+        let res = reg.register(&self.listener,
+            EventSet::readable(), PollOpt::level());
+        match res {
+            Ok(()) => Async::Continue(self, ()),
+            Err(_) => Async::Stop,
+        }
+    }
+    fn timeout(self, _timer: Timer, _scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        Async::Continue(self, None)
+    }
+    fn wakeup(self, _scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        Async::Continue(self, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::io::ErrorKind::ConnectionReset;
+    use std::net::{TcpStream as StdStream, SocketAddr};
+    use std::time::Duration as StdDuration;
+
+    use time::{SteadyTime, Duration};
+    use mio::tcp::{TcpStream, TcpListener};
+
+    use {Async, Scope, Void, HandlerConfig};
+    use transports::{StreamSocket, bind_reuse_port};
+    use transports::accept::Serve;
+    use transports::stream::{Stream, Protocol, Transport};
+    use super::{Runtime, Handoff};
+
+    struct Context {
+        index: usize,
+        accepted: usize,
+    }
+
+    /// Replies with the index of the loop to any input
+    struct Whoami;
+
+    impl Protocol<Context> for Whoami {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Whoami)
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            let len = trans.input().len();
+            trans.input().consume(len);
+            trans.output().extend(&[scope.index as u8]);
+            trans.close_after_flush();
+            Async::Continue(self, ())
+        }
+    }
+
+    /// Same as `Whoami` but rejects every second connection
+    struct Picky(Whoami);
+
+    impl Protocol<Context> for Picky {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            scope.accepted += 1;
+            if scope.accepted % 2 == 0 {
+                return None;
+            }
+            Some(Picky(Whoami))
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            self.0.data_received(trans, scope).map(Picky)
+        }
+    }
+
+    fn ask(addr: &SocketAddr) -> u8 {
+        let mut conn = StdStream::connect(addr).unwrap();
+        conn.write_all(b"?").unwrap();
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1);
+        buf[0]
+    }
+
+    #[test]
+    fn handoff_round_robin() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let workers = Runtime::new().threads(2).start_handoff(listener,
+            |index| Context { index, accepted: 0 },
+            |_, eloop, handler, handoff: Handoff<TcpStream>| {
+                let serve = Serve::<_, _, Stream<_, TcpStream, Whoami>>
+                    ::new(handoff);
+                handler.add_root(eloop, serve).unwrap();
+                Ok(())
+            }).unwrap();
+        assert_eq!(workers.len(), 2);
+        let loops: Vec<_> = (0..4).map(|_| ask(&addr)).collect();
+        assert_eq!(loops, vec![0, 1, 0, 1]);
+        workers.shutdown_gracefully(
+            SteadyTime::now() + Duration::seconds(1));
+        let summaries = workers.join().unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert!(summaries.iter().all(|s| s.map_or(false, |s| s.dropped == 0)));
+    }
+
+    #[test]
+    fn handoff_queue_full() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Connections in the backlog are handed off at once, faster than
+        // the loop takes the notifications
+        let conns: Vec<_> = (0..16).map(|_| {
+            let mut conn = StdStream::connect(addr).unwrap();
+            conn.set_read_timeout(Some(StdDuration::from_secs(5)))
+                .unwrap();
+            conn.write_all(b"?").unwrap();
+            conn
+        }).collect();
+        let mut config = HandlerConfig::new();
+        config.notify_capacity(1);
+        let workers = Runtime::new().threads(1).config(&config)
+            .start_handoff(listener,
+            |index| Context { index, accepted: 0 },
+            |_, eloop, handler, handoff: Handoff<TcpStream>| {
+                let serve = Serve::<_, _, Stream<_, TcpStream, Picky>>
+                    ::new(handoff);
+                handler.add_root(eloop, serve).unwrap();
+                Ok(())
+            }).unwrap();
+        let mut replies = Vec::new();
+        for mut conn in conns {
+            let mut buf = Vec::new();
+            match conn.read_to_end(&mut buf) {
+                Ok(_) => replies.extend(buf),
+                // Rejected connection is closed with unread input
+                Err(ref e) if e.kind() == ConnectionReset => {}
+                Err(e) => panic!("Error reading reply: {}", e),
+            }
+        }
+        assert_eq!(replies, vec![0; 8]);
+        workers.shutdown_gracefully(SteadyTime::now());
+        workers.join().unwrap();
+    }
+
+    #[test]
+    fn reuse_port() {
+        let first = bind_reuse_port(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = first.local_addr().unwrap();
+        let workers = Runtime::new().threads(2).start(
+            |index| Context { index, accepted: 0 },
+            move |_, eloop, handler| {
+                let listener = bind_reuse_port(&addr)?;
+                let serve = Serve::<_, _, Stream<_, TcpStream, Whoami>>
+                    ::new(listener);
+                handler.add_root(eloop, serve).unwrap();
+                Ok(())
+            }).unwrap();
+        // Otherwise connections may end up in the backlog of this one
+        drop(first);
+        for _ in 0..4 {
+            assert!(ask(&addr) < 2);
+        }
+        workers.shutdown_gracefully(SteadyTime::now());
+        assert_eq!(workers.join().unwrap().len(), 2);
+    }
+}
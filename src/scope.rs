@@ -0,0 +1,234 @@
+use std::ops::{Deref, DerefMut};
+
+use time::SteadyTime;
+use mio::Token;
+
+use handler::{LoopApi, Notify};
+use notify::{Notifier, WakeupError, send};
+use timer::Timers;
+
+
+/// A type that has no values
+///
+/// Used as a type of child state machine when there is no way to spawn
+/// one. E.g. `transports::stream::Protocol` gets `Scope<C, Void>`.
+pub enum Void {}
+
+impl Void {
+    pub fn unreachable<T>(self) -> T {
+        match self {}
+    }
+}
+
+/// Per-event data passed to every action of the state machine
+///
+/// Dereferences to the context, so can be used in place of it.
+pub struct Scope<'a, C: 'a, M: 'a> {
+    token: Token,
+    generation: u64,
+    now: SteadyTime,
+    io_budget: usize,
+    context: &'a mut C,
+    eloop: &'a mut dyn LoopApi,
+    spawned: &'a mut Vec<M>,
+    timers: &'a mut Timers,
+}
+
+impl<'a, C, M> Scope<'a, C, M> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(token: Token, generation: u64, now: SteadyTime,
+        io_budget: usize,
+        context: &'a mut C, eloop: &'a mut dyn LoopApi,
+        spawned: &'a mut Vec<M>, timers: &'a mut Timers)
+        -> Scope<'a, C, M>
+    {
+        Scope {
+            token,
+            generation,
+            now,
+            io_budget,
+            context,
+            eloop,
+            spawned,
+            timers,
+        }
+    }
+    /// Token of the state machine in the main loop
+    pub fn token(&self) -> Token {
+        self.token
+    }
+    /// Time when the event processing started
+    pub fn now(&self) -> SteadyTime {
+        self.now
+    }
+    /// Bytes a machine may read or write in one event before rescheduling
+    ///
+    /// See `HandlerConfig::io_budget`
+    pub fn io_budget(&self) -> usize {
+        self.io_budget
+    }
+    /// Returns notifier which wakes up this state machine
+    pub fn notifier(&self) -> Notifier {
+        Notifier::new(self.token, self.generation, self.eloop.channel())
+    }
+    /// Schedules `ready` with empty event set on the next loop iteration
+    ///
+    /// Used to yield to other state machines when there is more work to do.
+    /// Fails if the notification queue is full.
+    pub fn reschedule(&mut self) -> Result<(), WakeupError> {
+        send(&self.eloop.channel(), Notify::Ready(self.token, self.generation))
+    }
+    /// Adds state machine to the main loop after the current action
+    ///
+    /// If machine can't be added `EventMachine::spawn_failed` is called
+    pub fn spawn(&mut self, machine: M) {
+        self.spawned.push(machine);
+    }
+    /// Schedules `EventMachine::timeout` at the deadline
+    ///
+    /// Like with `Async::Timeout` timer is cleared at next state transition,
+    /// but this one allows to return `Async::Continue` with a child. When
+    /// both are used the earlier deadline wins.
+    pub fn set_timeout(&mut self, deadline: SteadyTime) {
+        self.timers.request(Some(deadline));
+    }
+    /// Clears the timeout set by `set_timeout`
+    pub fn clear_timeout(&mut self) {
+        self.timers.request(None);
+    }
+    /// Schedules `EventMachine::timeout` with `Timer::Id(id)` at deadline
+    ///
+    /// Unlike `set_timeout` the timer is kept across state transitions
+    /// until it fires or is cleared. Setting the same `id` again replaces
+    /// the timer.
+    pub fn set_timer(&mut self, id: u64, deadline: SteadyTime) {
+        self.timers.set(id, deadline);
+    }
+    /// Cancels the timer set by `set_timer`, returns its deadline if any
+    pub fn clear_timer(&mut self, id: u64) -> Option<SteadyTime> {
+        self.timers.clear(id)
+    }
+    /// Returns the deadline of the timer set by `set_timer`
+    pub fn timer(&self, id: u64) -> Option<SteadyTime> {
+        self.timers.get(id)
+    }
+    /// Stops the main loop after processing current events
+    pub fn shutdown_loop(&mut self) {
+        self.eloop.shutdown();
+    }
+    /// Starts graceful shutdown of the main loop
+    ///
+    /// See `Handler::shutdown_gracefully`. Fails if the notification queue
+    /// is full.
+    pub fn shutdown_gracefully(&mut self, deadline: SteadyTime)
+        -> Result<(), WakeupError>
+    {
+        send(&self.eloop.channel(), Notify::Shutdown(deadline))
+    }
+    /// Runs the function with the scope for the wrapped state machine
+    ///
+    /// This is useful when composing state machines: the state machines
+    /// spawned by `fun` are converted by `wrapper` to the outer type.
+    pub fn wrap<N, W, F, R>(&mut self, wrapper: W, fun: F) -> R
+        where W: Fn(N) -> M, F: FnOnce(&mut Scope<C, N>) -> R,
+    {
+        let mut spawned = Vec::new();
+        let result = fun(&mut Scope {
+            token: self.token,
+            generation: self.generation,
+            now: self.now,
+            io_budget: self.io_budget,
+            context: &mut *self.context,
+            eloop: &mut *self.eloop,
+            spawned: &mut spawned,
+            timers: &mut *self.timers,
+        });
+        self.spawned.extend(spawned.into_iter().map(wrapper));
+        result
+    }
+}
+
+impl<'a, C, M> Deref for Scope<'a, C, M> {
+    type Target = C;
+    fn deref(&self) -> &C {
+        self.context
+    }
+}
+
+impl<'a, C, M> DerefMut for Scope<'a, C, M> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.context
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::{channel, Sender};
+
+    use time::Duration;
+    use mio::{EventLoop, EventSet, Token};
+
+    use {Async, EventMachine, Handler, Timer};
+    use handler::Registrator;
+    use super::Scope;
+
+    #[derive(Default)]
+    struct Context {
+        tokens: Vec<Token>,
+    }
+
+    enum Machine {
+        Parent(Sender<()>),
+        Child(Sender<()>),
+    }
+
+    impl EventMachine<Context> for Machine {
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            Async::Continue(self, None)
+        }
+        fn register(self, _reg: &mut dyn Registrator) -> Async<Self, ()> {
+            if let Machine::Child(ref tx) = self {
+                tx.send(()).unwrap();
+            }
+            Async::Continue(self, ())
+        }
+        fn timeout(self, _timer: Timer, scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            let token = scope.token();
+            scope.tokens.push(token);
+            scope.shutdown_loop();
+            Async::Continue(self, None)
+        }
+        fn wakeup(self, scope: &mut Scope<Context, Self>)
+            -> Async<Self, Option<Self>>
+        {
+            let token = scope.token();
+            scope.tokens.push(token);
+            if scope.tokens.len() == 1 {
+                if let Machine::Parent(ref tx) = self {
+                    scope.spawn(Machine::Child(tx.clone()));
+                }
+                scope.notifier().wakeup().unwrap();
+            } else {
+                let deadline = scope.now() + Duration::milliseconds(50);
+                scope.set_timeout(deadline);
+            }
+            Async::Continue(self, None)
+        }
+    }
+
+    #[test]
+    fn spawn_timeout_shutdown() {
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let (tx, rx) = channel();
+        let tok = handler.add_root(&mut eloop, Machine::Parent(tx)).unwrap();
+        handler.notifier(&eloop, tok).unwrap().wakeup().unwrap();
+        eloop.run(&mut handler).unwrap();
+        rx.try_recv().unwrap();  // child is registered
+        assert_eq!(handler.context().tokens, vec![tok, tok, tok]);
+    }
+}
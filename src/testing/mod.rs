@@ -0,0 +1,827 @@
+//! Loop-free harness for unit testing stream protocols
+//!
+//! Available with the `testing` cargo feature. The `MockSocket` is scripted
+//! by the test: it returns the queued input chunk by chunk, and accepts
+//! writes up to the configured limit. The `Driver` owns a
+//! `Stream<C, MockSocket, P>` and steps it through the events the way the
+//! main loop would do, but without any real polling:
+//!
+//! ```
+//! # use rotor::{Async, Scope, Void};
+//! # use rotor::transports::StreamSocket;
+//! # use rotor::transports::stream::{Protocol, Transport};
+//! use rotor::testing::{MockSocket, Driver};
+//!
+//! struct Echo;
+//!
+//! impl Protocol<()> for Echo {
+//!     fn accepted<S: StreamSocket>(_conn: &mut S,
+//!         _scope: &mut Scope<(), Void>)
+//!         -> Option<Self>
+//!     {
+//!         Some(Echo)
+//!     }
+//!     fn data_received(self, trans: &mut Transport,
+//!         _scope: &mut Scope<(), Void>)
+//!         -> Async<Self, ()>
+//!     {
+//!         let data = trans.input()[..].to_vec();
+//!         trans.input().consume(data.len());
+//!         trans.output().extend(&data);
+//!         Async::Continue(self, ())
+//!     }
+//! }
+//!
+//! let sock = MockSocket::new();
+//! let mut driver = Driver::<_, Echo>::accept((), &sock);
+//! sock.input(b"hello");
+//! driver.readable();
+//! assert_eq!(sock.output(), b"hello");
+//! ```
+//!
+//! To test the composition of the machines use the `Simulation`, which runs
+//! the whole `Handler` with mock sockets and virtual time.
+use std::io;
+use std::cmp::min;
+use std::io::{Read, Write};
+use std::io::ErrorKind::WouldBlock;
+use std::collections::VecDeque;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+
+use time::{SteadyTime, Duration};
+use mio::{Token, EventSet, PollOpt, Evented, Selector};
+
+use {Async, EventMachine, Scope, Error, Timer};
+use config::DEFAULT_IO_BUDGET;
+use handler::{Abort, LoopApi, Registrator, Notify};
+use notify::{Notifier, Channel};
+use timer::{Timers, Wheel};
+use transports::accept::Init;
+use transports::stream::{Stream, Protocol, DEFAULT_CONNECT_TIMEOUT_MS};
+
+pub use self::simulation::Simulation;
+
+mod simulation;
+
+/// Stream run by the `Driver`
+pub type MockStream<C, P> = Stream<C, MockSocket, P>;
+
+/// Token of the stream run by the `Driver`
+const TOKEN: Token = Token(0);
+
+
+enum Input {
+    Data(Vec<u8>),
+    WouldBlock,
+    Error(io::ErrorKind),
+    Eof,
+}
+
+struct State {
+    input: VecDeque<Input>,
+    output: Vec<u8>,
+    /// Bytes that may be written until `WouldBlock`, `None` is unlimited
+    write_limit: Option<usize>,
+    write_errors: VecDeque<io::ErrorKind>,
+    /// Token, interest and options the socket is registered with
+    registration: Option<(Token, EventSet, PollOpt)>,
+    /// Readiness changes not delivered by the `Simulation` yet
+    events: EventSet,
+}
+
+/// Scriptable socket
+///
+/// The data is read from and written to the queues in memory. Clones share
+/// the state, so the test keeps a clone to feed the input and inspect the
+/// output of the stream. Reads return `WouldBlock` when there is no more
+/// input queued.
+///
+/// The socket is backed by a real unix socket pair, the file descriptor is
+/// only used for the socket options (`shutdown`, `SO_LINGER`, `SO_ERROR`),
+/// so that `is_write_shut()` can be checked.
+#[derive(Clone)]
+pub struct MockSocket {
+    state: Arc<Mutex<State>>,
+    sock: Arc<UnixStream>,
+    peer: Arc<UnixStream>,
+}
+
+/// Runs the stream with the mock socket without the main loop
+///
+/// Each event is followed by the notifications that stream sent itself
+/// (e.g. when it's out of the io budget) and timers are fired when time is
+/// advanced, so every method leaves the stream waiting for the next event.
+pub struct Driver<C, P: Protocol<C>> {
+    stream: Option<MockStream<C, P>>,
+    context: C,
+    now: SteadyTime,
+    timers: Timers,
+    wheel: Wheel,
+    selector: Selector,
+    sender: mpsc::Sender<Notify>,
+    notifications: mpsc::Receiver<Notify>,
+    loop_shut_down: bool,
+    error: Option<Error>,
+}
+
+struct MockLoop<'a> {
+    selector: &'a mut Selector,
+    sender: &'a mpsc::Sender<Notify>,
+    shut_down: &'a mut bool,
+}
+
+struct MockRegistrator<'a> {
+    selector: &'a mut Selector,
+    sender: &'a mpsc::Sender<Notify>,
+    now: SteadyTime,
+    failed: bool,
+}
+
+impl MockSocket {
+    pub fn new() -> MockSocket {
+        let (sock, peer) = UnixStream::pair()
+            .expect("can create socket pair");
+        peer.set_nonblocking(true).expect("can set socket nonblocking");
+        MockSocket {
+            state: Arc::new(Mutex::new(State {
+                input: VecDeque::new(),
+                output: Vec::new(),
+                write_limit: None,
+                write_errors: VecDeque::new(),
+                registration: None,
+                events: EventSet::none(),
+            })),
+            sock: Arc::new(sock),
+            peer: Arc::new(peer),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("mock socket is not poisoned")
+    }
+    /// Queues data for reading, a single read never crosses the chunks
+    pub fn input(&self, data: &[u8]) {
+        self.push_input(Input::Data(data.to_vec()));
+    }
+    /// Makes the read after the queued input return `WouldBlock`
+    pub fn would_block(&self) {
+        self.push_input(Input::WouldBlock);
+    }
+    /// Makes the read after the queued input fail
+    pub fn read_error(&self, kind: io::ErrorKind) {
+        self.push_input(Input::Error(kind));
+    }
+    /// Makes all reads after the queued input return end of stream
+    pub fn eof(&self) {
+        self.push_input(Input::Eof);
+    }
+    fn push_input(&self, input: Input) {
+        let mut state = self.lock();
+        state.input.push_back(input);
+        state.events.insert(EventSet::readable());
+    }
+    /// Number of bytes accepted until writes return `WouldBlock`
+    ///
+    /// A write which doesn't fit into the limit is partial. Use `None` to
+    /// accept everything, which is the default.
+    pub fn write_limit(&self, bytes: Option<usize>) {
+        let mut state = self.lock();
+        state.write_limit = bytes;
+        if bytes != Some(0) {
+            state.events.insert(EventSet::writable());
+        }
+    }
+    /// Makes the next write fail
+    pub fn write_error(&self, kind: io::ErrorKind) {
+        self.lock().write_errors.push_back(kind);
+    }
+    /// Takes the data written to the socket so far
+    pub fn output(&self) -> Vec<u8> {
+        let mut state = self.lock();
+        let data = state.output.clone();
+        state.output.clear();
+        data
+    }
+    /// Whether the write side of the socket was shut down by the stream
+    pub fn is_write_shut(&self) -> bool {
+        (&*self.peer).read(&mut [0u8]).ok() == Some(0)
+    }
+    /// Whether the socket is registered in the `Simulation` or `Driver`
+    pub fn is_registered(&self) -> bool {
+        self.lock().registration.is_some()
+    }
+    /// Events which the `Simulation` may deliver for the socket now
+    fn pending(&self) -> Option<(Token, EventSet)> {
+        let state = self.lock();
+        let (token, interest, opts) = state.registration?;
+        let events = if opts.is_edge() {
+            state.events
+        } else {
+            state.readiness()
+        } & interest;
+        if events == EventSet::none() {
+            None
+        } else {
+            Some((token, events))
+        }
+    }
+    /// Marks the pending events as delivered
+    fn take_pending(&self) -> Option<(Token, EventSet)> {
+        let result = self.pending();
+        if let Some((_, events)) = result {
+            let mut state = self.lock();
+            state.events.remove(events);
+            if let Some((_, ref mut interest, opts)) = state.registration {
+                if opts.is_oneshot() {
+                    *interest = EventSet::none();
+                }
+            }
+        }
+        result
+    }
+    fn set_registration(&self, token: Token, interest: EventSet,
+        opts: PollOpt)
+    {
+        let mut state = self.lock();
+        // Like epoll, reports the current state on each registration
+        state.events = state.readiness();
+        state.registration = Some((token, interest, opts));
+    }
+}
+
+impl State {
+    fn readiness(&self) -> EventSet {
+        let mut events = EventSet::none();
+        if !self.input.is_empty() {
+            events.insert(EventSet::readable());
+        }
+        if self.write_limit != Some(0) {
+            events.insert(EventSet::writable());
+        }
+        events
+    }
+}
+
+impl Default for MockSocket {
+    fn default() -> MockSocket {
+        MockSocket::new()
+    }
+}
+
+impl Read for MockSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        match state.input.pop_front() {
+            None | Some(Input::WouldBlock) => Err(WouldBlock.into()),
+            Some(Input::Error(kind)) => Err(kind.into()),
+            Some(Input::Eof) => {
+                state.input.push_front(Input::Eof);
+                Ok(0)
+            }
+            Some(Input::Data(mut data)) => {
+                let bytes = min(buf.len(), data.len());
+                buf[..bytes].copy_from_slice(&data[..bytes]);
+                if bytes < data.len() {
+                    data.drain(..bytes);
+                    state.input.push_front(Input::Data(data));
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Write for MockSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        if let Some(kind) = state.write_errors.pop_front() {
+            return Err(kind.into());
+        }
+        let bytes = match state.write_limit {
+            Some(0) => return Err(WouldBlock.into()),
+            Some(ref mut limit) => {
+                let bytes = min(*limit, buf.len());
+                *limit -= bytes;
+                bytes
+            }
+            None => buf.len(),
+        };
+        state.output.extend_from_slice(&buf[..bytes]);
+        Ok(bytes)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Only records the registration, the selector is never used
+impl Evented for MockSocket {
+    fn register(&self, _selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        if self.is_registered() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.set_registration(token, interest, opts);
+        Ok(())
+    }
+    fn reregister(&self, _selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        if !self.is_registered() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.set_registration(token, interest, opts);
+        Ok(())
+    }
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        match self.lock().registration.take() {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+impl AsRawFd for MockSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl<'a> LoopApi for MockLoop<'a> {
+    fn register(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        io.register(self.selector, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        io.reregister(self.selector, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()> {
+        io.deregister(self.selector)
+    }
+    fn channel(&self) -> Channel {
+        Channel::Queue(self.sender.clone())
+    }
+    fn shutdown(&mut self) {
+        *self.shut_down = true;
+    }
+}
+
+impl<'a> MockRegistrator<'a> {
+    /// Remembers the failure, the handler stops the machine in this case
+    fn check(&mut self, result: io::Result<()>) -> io::Result<()> {
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+impl<'a> Registrator for MockRegistrator<'a> {
+    fn register(&mut self, io: &dyn Evented, interest: EventSet,
+        opt: PollOpt)
+        -> io::Result<()>
+    {
+        let result = io.register(self.selector, TOKEN, interest, opt);
+        self.check(result)
+    }
+    fn reregister(&mut self, io: &dyn Evented,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        let result = io.reregister(self.selector, TOKEN, interest, opt);
+        self.check(result)
+    }
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()> {
+        let result = io.deregister(self.selector);
+        self.check(result)
+    }
+    fn notifier(&mut self) -> Notifier {
+        Notifier::new(TOKEN, 0, Channel::Queue(self.sender.clone()))
+    }
+    fn now(&self) -> SteadyTime {
+        self.now
+    }
+}
+
+impl<C, P: Protocol<C>> Driver<C, P> {
+    fn new(context: C) -> Driver<C, P> {
+        let now = SteadyTime::now();
+        let (sender, notifications) = mpsc::channel();
+        Driver {
+            stream: None,
+            context,
+            now,
+            timers: Timers::new(),
+            wheel: Wheel::new(1024, now),
+            selector: Selector::new().expect("can create selector"),
+            sender,
+            notifications,
+            loop_shut_down: false,
+            error: None,
+        }
+    }
+    /// Creates the stream the same way `accept::Serve` does
+    ///
+    /// The stream is closed immediately if `Protocol::accepted` returns
+    /// `None`.
+    pub fn accept(context: C, socket: &MockSocket) -> Driver<C, P> {
+        let mut driver = Driver::new(context);
+        let mut stream = None;
+        driver.action(|scope| {
+            stream = MockStream::<C, P>::accept(socket.clone(), scope);
+        });
+        if let Some(stream) = stream {
+            driver.register(stream);
+        }
+        driver
+    }
+    /// Creates the stream in the connecting state
+    ///
+    /// The connection is established on the first writable event, the
+    /// connect timeout is the same as for `Stream::connect`.
+    pub fn connect(context: C, socket: &MockSocket, protocol: P)
+        -> Driver<C, P>
+    {
+        let mut driver = Driver::new(context);
+        driver.register(Stream::connecting(socket.clone(), protocol,
+            Duration::milliseconds(DEFAULT_CONNECT_TIMEOUT_MS)));
+        driver
+    }
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+    pub fn context_mut(&mut self) -> &mut C {
+        &mut self.context
+    }
+    /// Current virtual time, it only moves with `advance()`
+    pub fn now(&self) -> SteadyTime {
+        self.now
+    }
+    /// Whether the stream has stopped (either closed or failed)
+    pub fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+    /// The error the stream has failed with
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+    /// Whether `Scope::shutdown_loop()` was called by the protocol
+    pub fn is_loop_shut_down(&self) -> bool {
+        self.loop_shut_down
+    }
+    /// When the next timer of the stream expires
+    pub fn deadline(&self) -> Option<SteadyTime> {
+        self.wheel.next_wakeup()
+    }
+    /// Delivers readiness event to the stream
+    pub fn ready(&mut self, events: EventSet) {
+        self.transition(|m, scope| m.ready(events, scope));
+        self.notifications();
+    }
+    /// Delivers the readable event, i.e. stream reads the queued input
+    pub fn readable(&mut self) {
+        self.ready(EventSet::readable());
+    }
+    /// Delivers the writable event, i.e. stream flushes its output
+    pub fn writable(&mut self) {
+        self.ready(EventSet::writable());
+    }
+    /// Wakes up the stream as `Notifier::wakeup()` does
+    pub fn wakeup(&mut self) {
+        self.transition(|m, scope| m.wakeup(scope));
+        self.notifications();
+    }
+    /// Calls `EventMachine::shutdown` as the graceful shutdown of loop does
+    pub fn shutdown(&mut self) {
+        self.transition(|m, scope| m.shutdown(scope));
+        self.notifications();
+    }
+    /// Moves the time forward and fires expired timers
+    pub fn advance(&mut self, duration: Duration) {
+        self.now = self.now + duration;
+        let mut expired = Vec::new();
+        self.wheel.expire(self.now, &mut expired);
+        for (key, _, _) in expired {
+            let now = self.now;
+            if let Some(timer) = self.timers.fired(key, now) {
+                self.timeout(timer);
+            }
+        }
+        self.notifications();
+    }
+    fn timeout(&mut self, timer: Timer) {
+        self.transition(|m, scope| m.timeout(timer, scope));
+    }
+    /// Processes the notifications sent during the previous events
+    fn notifications(&mut self) {
+        while let Ok(msg) = self.notifications.try_recv() {
+            match msg {
+                Notify::Fsm(..) => self.transition(|m, s| m.wakeup(s)),
+                Notify::Ready(..) => {
+                    self.transition(|m, s| m.ready(EventSet::none(), s));
+                }
+                Notify::Shutdown(_) => self.transition(|m, s| m.shutdown(s)),
+                // There is no other loop to move the stream to
+                Notify::Detach(..) | Notify::Inbox => {}
+                // Io events are never deferred by the driver
+                Notify::Deferred => {}
+            }
+        }
+    }
+    /// Runs the function with the scope for the stream
+    fn action<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Scope<C, MockStream<C, P>>) -> R
+    {
+        let mut spawned = Vec::new();
+        let mut eloop = MockLoop {
+            selector: &mut self.selector,
+            sender: &self.sender,
+            shut_down: &mut self.loop_shut_down,
+        };
+        let result = f(&mut Scope::new(TOKEN, 0, self.now, DEFAULT_IO_BUDGET,
+            &mut self.context, &mut eloop, &mut spawned, &mut self.timers));
+        assert!(spawned.is_empty(), "stream never spawns machines");
+        result
+    }
+    fn registrator(&mut self) -> MockRegistrator<'_> {
+        MockRegistrator {
+            selector: &mut self.selector,
+            sender: &self.sender,
+            now: self.now,
+            failed: false,
+        }
+    }
+    fn register(&mut self, stream: MockStream<C, P>) {
+        let mut reg = self.registrator();
+        let result = stream.register(&mut reg).map_result(|()| None);
+        let result = if reg.failed {
+            Async::Error(Error::Register(Abort::RegisterFailed))
+        } else {
+            result
+        };
+        self.replace(result);
+    }
+    /// Calls `EventMachine::reregister` after the action as handler does
+    fn reregister(&mut self,
+        result: Async<MockStream<C, P>, Option<MockStream<C, P>>>)
+        -> Async<MockStream<C, P>, Option<MockStream<C, P>>>
+    {
+        let mut reg = self.registrator();
+        let result = match result {
+            Async::Continue(mut stream, child) => {
+                stream.reregister(&mut reg);
+                Async::Continue(stream, child)
+            }
+            Async::Timeout(mut stream, deadline) => {
+                stream.reregister(&mut reg);
+                Async::Timeout(stream, deadline)
+            }
+            result => result,
+        };
+        if reg.failed {
+            Async::Error(Error::Register(Abort::RegisterFailed))
+        } else {
+            result
+        }
+    }
+    fn transition<F>(&mut self, f: F)
+        where F: FnOnce(MockStream<C, P>,
+                        &mut Scope<C, MockStream<C, P>>)
+                 -> Async<MockStream<C, P>, Option<MockStream<C, P>>>
+    {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let result = self.action(|scope| f(stream, scope));
+        let result = self.reregister(result);
+        self.replace(result);
+    }
+    fn replace(&mut self,
+        result: Async<MockStream<C, P>, Option<MockStream<C, P>>>)
+    {
+        let (stream, deadline) = match result {
+            Async::Continue(stream, _) => (stream, None),
+            Async::Timeout(stream, deadline) => (stream, Some(deadline)),
+            Async::Stop => {
+                self.timers.cancel(&mut self.wheel);
+                return;
+            }
+            Async::Error(e) => {
+                self.timers.cancel(&mut self.wheel);
+                self.error = Some(e);
+                return;
+            }
+        };
+        self.timers.transition(deadline);
+        self.timers.schedule(&mut self.wheel, TOKEN, 0);
+        self.stream = Some(stream);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use time::Duration;
+
+    use {Async, Scope, Void, Error, Timer};
+    use transports::StreamSocket;
+    use transports::stream::{Protocol, Transport, StreamConfig, TimeoutKind};
+    use super::{MockSocket, Driver};
+
+    #[derive(Default)]
+    struct Context {
+        errors: usize,
+        stream_timeouts: usize,
+        timeouts: usize,
+    }
+
+    /// Echoes the input, shuts down the write side on "bye"
+    struct Echo;
+
+    impl Protocol<Context> for Echo {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Echo)
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.idle_timeout(Duration::seconds(1));
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            _scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            let data = trans.input()[..].to_vec();
+            trans.input().consume(data.len());
+            trans.output().extend(&data);
+            if data == b"bye" {
+                trans.shutdown_write();
+            }
+            Async::Continue(self, ())
+        }
+        fn error_happened(_err: &Error, scope: &mut Scope<Context, Void>)
+        {
+            scope.errors += 1;
+        }
+    }
+
+    type EchoDriver = Driver<Context, Echo>;
+
+    /// Sets own deadline at the same time as the read timeout expires
+    struct Waiter;
+
+    impl Protocol<Context> for Waiter {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Waiter)
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.read_timeout(Duration::milliseconds(100));
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            let len = trans.input().len();
+            trans.input().consume(len);
+            Async::Timeout(self, scope.now() + Duration::milliseconds(100))
+        }
+        fn stream_timeout(self, _kind: TimeoutKind, _trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.stream_timeouts += 1;
+            Async::Continue(self, ())
+        }
+        fn timeout(self, _timer: Timer, scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.timeouts += 1;
+            Async::Continue(self, ())
+        }
+    }
+
+    #[test]
+    fn registration() {
+        let sock = MockSocket::new();
+        let mut driver = EchoDriver::accept(Context::default(), &sock);
+        assert!(sock.is_registered());
+        sock.input(b"x");
+        driver.readable();
+        assert!(sock.is_registered());
+        // The socket is already registered by the first stream
+        let second = EchoDriver::accept(Context::default(), &sock);
+        assert!(second.is_closed());
+        assert!(matches!(second.error(), Some(&Error::Register(_))));
+    }
+
+    #[test]
+    fn partial_writes() {
+        let sock = MockSocket::new();
+        let mut driver = EchoDriver::accept(Context::default(), &sock);
+        sock.input(b"hel");
+        driver.readable();
+        assert_eq!(sock.output(), b"hel");
+        sock.write_limit(Some(2));
+        sock.input(b"lo world");
+        driver.readable();
+        assert_eq!(sock.output(), b"lo");
+        driver.writable();
+        assert_eq!(sock.output(), b"");
+        sock.write_limit(Some(100));
+        driver.writable();
+        assert_eq!(sock.output(), b" world");
+        assert!(!driver.is_closed());
+    }
+
+    #[test]
+    fn shutdown_write() {
+        let sock = MockSocket::new();
+        let mut driver = EchoDriver::accept(Context::default(), &sock);
+        sock.write_limit(Some(0));
+        sock.input(b"bye");
+        driver.readable();
+        assert!(!sock.is_write_shut());
+        sock.write_limit(None);
+        driver.writable();
+        assert_eq!(sock.output(), b"bye");
+        assert!(sock.is_write_shut());
+        sock.eof();
+        driver.readable();
+        assert!(driver.is_closed());
+        assert!(driver.error().is_none());
+    }
+
+    #[test]
+    fn read_error() {
+        let sock = MockSocket::new();
+        let mut driver = EchoDriver::accept(Context::default(), &sock);
+        sock.input(b"x");
+        sock.would_block();
+        sock.read_error(ErrorKind::ConnectionReset);
+        driver.readable();
+        assert_eq!(sock.output(), b"x");
+        assert!(!driver.is_closed());
+        driver.readable();
+        assert!(driver.is_closed());
+        assert!(driver.error().is_some());
+        assert_eq!(driver.context().errors, 1);
+    }
+
+    #[test]
+    fn shutdown_flushes() {
+        let sock = MockSocket::new();
+        let mut driver = EchoDriver::accept(Context::default(), &sock);
+        sock.write_limit(Some(0));
+        sock.input(b"x");
+        driver.readable();
+        driver.shutdown();
+        assert!(!driver.is_closed());
+        sock.write_limit(None);
+        driver.writable();
+        assert_eq!(sock.output(), b"x");
+        assert!(driver.is_closed());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let sock = MockSocket::new();
+        let mut driver = EchoDriver::accept(Context::default(), &sock);
+        driver.advance(Duration::milliseconds(600));
+        sock.input(b"ping");
+        driver.readable();
+        driver.advance(Duration::milliseconds(999));
+        assert!(!driver.is_closed());
+        driver.advance(Duration::milliseconds(1));
+        assert!(driver.is_closed());
+        assert!(matches!(driver.error(), Some(&Error::Timeout)));
+    }
+
+    #[test]
+    fn simultaneous_timeouts() {
+        let sock = MockSocket::new();
+        let mut driver = Driver::<_, Waiter>::accept(Context::default(), &sock);
+        sock.input(b"x");
+        driver.readable();
+        driver.advance(Duration::milliseconds(100));
+        assert_eq!(driver.context().stream_timeouts, 1);
+        assert_eq!(driver.context().timeouts, 1);
+        assert!(!driver.is_closed());
+    }
+}
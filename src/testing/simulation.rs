@@ -0,0 +1,375 @@
+use std::io;
+use std::sync::mpsc;
+
+use time::{SteadyTime, Duration};
+use mio::{Token, EventSet, PollOpt, Evented, Selector};
+
+use {EventMachine, Handler, HandlerConfig, Notifier, Mailbox};
+use clock::{Clock, ManualClock};
+use handler::{Abort, LoopApi, Notify};
+use notify::Channel;
+use super::MockSocket;
+
+/// Number of steps after which `run()` decides the loop never settles
+pub const DEFAULT_MAX_STEPS: usize = 100_000;
+
+
+/// Runs the whole `Handler` against the simulated poller
+///
+/// Everything runs in a single thread and nothing is real: sockets are
+/// `MockSocket`s created by `socket()`, and the time is virtual, it only
+/// moves with `advance()`. At each step the scheduler picks one of the
+/// pending events (a readiness of some socket or a notification) at random,
+/// so different seeds deliver the events in different order. The same seed
+/// always gives the same order, so the failure can be reproduced.
+///
+/// Real file descriptors may be registered too, but they never get events.
+/// Mock sockets are not deregistered when the machine is stopped, their
+/// events are ignored by the handler the same way as spurious events are.
+pub struct Simulation<C, M: EventMachine<C>> {
+    handler: Handler<C, M>,
+    clock: ManualClock,
+    eloop: SimLoop,
+    notifications: mpsc::Receiver<Notify>,
+    pending: Vec<Notify>,
+    sockets: Vec<MockSocket>,
+    random: u64,
+    max_steps: usize,
+}
+
+struct SimLoop {
+    selector: Selector,
+    sender: mpsc::Sender<Notify>,
+    shut_down: bool,
+}
+
+enum Event {
+    Io(usize),
+    Notify(usize),
+}
+
+impl LoopApi for SimLoop {
+    fn register(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        io.register(&mut self.selector, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &dyn Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        io.reregister(&mut self.selector, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &dyn Evented) -> io::Result<()> {
+        io.deregister(&mut self.selector)
+    }
+    fn channel(&self) -> Channel {
+        Channel::Queue(self.sender.clone())
+    }
+    fn shutdown(&mut self) {
+        self.shut_down = true;
+    }
+}
+
+impl<C, M: EventMachine<C>> Simulation<C, M> {
+    /// Creates simulation with default handler configuration
+    pub fn new(context: C, seed: u64) -> Simulation<C, M> {
+        Simulation::configured(context, &HandlerConfig::new(), seed)
+    }
+    pub fn configured(context: C, config: &HandlerConfig, seed: u64)
+        -> Simulation<C, M>
+    {
+        let clock = ManualClock::new();
+        let (sender, notifications) = mpsc::channel();
+        Simulation {
+            handler: Handler::with_clock(context, config, clock.clone()),
+            clock,
+            eloop: SimLoop {
+                selector: Selector::new().expect("can create selector"),
+                sender,
+                shut_down: false,
+            },
+            notifications,
+            pending: Vec::new(),
+            sockets: Vec::new(),
+            // Zero is the fixed point of xorshift
+            random: seed ^ 0x9E37_79B9_7F4A_7C15,
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+    /// Sets the limit of steps in a single `run()`
+    pub fn max_steps(&mut self, steps: usize) -> &mut Self {
+        self.max_steps = steps;
+        self
+    }
+    pub fn handler(&self) -> &Handler<C, M> {
+        &self.handler
+    }
+    pub fn context(&self) -> &C {
+        self.handler.context()
+    }
+    pub fn context_mut(&mut self) -> &mut C {
+        self.handler.context_mut()
+    }
+    /// Current virtual time
+    pub fn now(&self) -> SteadyTime {
+        self.clock.now()
+    }
+    /// Whether the loop is stopped by `Scope::shutdown_loop()` or by the
+    /// graceful shutdown
+    pub fn is_shut_down(&self) -> bool {
+        self.eloop.shut_down
+    }
+    /// Creates a socket whose readiness is delivered by the simulation
+    pub fn socket(&mut self) -> MockSocket {
+        let sock = MockSocket::new();
+        self.sockets.push(sock.clone());
+        sock
+    }
+    /// Returns a notifier which wakes up the machine `token`
+    ///
+    /// Returns `None` if there is no such machine.
+    pub fn notifier(&self, token: Token) -> Option<Notifier> {
+        self.handler.notifier(&self.eloop, token)
+    }
+    pub fn add_root(&mut self, m: M) -> Result<Token, Abort> {
+        self.handler.add_machine(&mut self.eloop, m)
+    }
+    /// Removes the machine from the loop, see `Handler::detach`
+    pub fn detach(&mut self, token: Token) -> Option<M> {
+        self.handler.detach_machine(&mut self.eloop, token)
+    }
+    /// Returns the mailbox which adds machines to the simulated loop
+    pub fn mailbox(&self) -> Mailbox<M> {
+        self.handler.mailbox(&self.eloop)
+    }
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        self.random.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    /// Delivers a single event picked at random
+    ///
+    /// Returns `false` if there are no pending events.
+    pub fn step(&mut self) -> bool {
+        while let Ok(msg) = self.notifications.try_recv() {
+            self.pending.push(msg);
+        }
+        let mut events = Vec::new();
+        for (idx, sock) in self.sockets.iter().enumerate() {
+            if sock.pending().is_some() {
+                events.push(Event::Io(idx));
+            }
+        }
+        events.extend((0..self.pending.len()).map(Event::Notify));
+        if events.is_empty() {
+            return false;
+        }
+        let idx = (self.next_random() % events.len() as u64) as usize;
+        match events.swap_remove(idx) {
+            Event::Io(idx) => {
+                if let Some((token, ev)) = self.sockets[idx].take_pending() {
+                    self.handler.io_ready(&mut self.eloop, token, ev);
+                }
+            }
+            Event::Notify(idx) => {
+                let msg = self.pending.remove(idx);
+                self.handler.notified(&mut self.eloop, msg);
+            }
+        }
+        self.handler.check_shutdown(&mut self.eloop);
+        true
+    }
+    /// Delivers events until there are no more, returns number of steps
+    ///
+    /// # Panics
+    ///
+    /// When the events don't end after `max_steps`, i.e. machines wake up
+    /// each other infinitely.
+    pub fn run(&mut self) -> usize {
+        let mut steps = 0;
+        while self.step() {
+            steps += 1;
+            assert!(steps < self.max_steps,
+                "simulation doesn't settle after {} steps", steps);
+        }
+        steps
+    }
+    /// Moves the time forward firing timers in order
+    ///
+    /// Events are run to completion before each timer and at the end.
+    pub fn advance(&mut self, duration: Duration) {
+        let target = self.clock.now() + duration;
+        loop {
+            self.run();
+            match self.handler.next_timer() {
+                Some(deadline) if deadline <= target => {
+                    let now = self.clock.now();
+                    if deadline > now {
+                        self.clock.advance(deadline - now);
+                    }
+                    self.handler.expire_timers(&mut self.eloop);
+                    self.handler.check_shutdown(&mut self.eloop);
+                }
+                _ => break,
+            }
+        }
+        let now = self.clock.now();
+        self.clock.advance(target - now);
+        self.handler.expire_timers(&mut self.eloop);
+        self.handler.check_shutdown(&mut self.eloop);
+        self.run();
+    }
+    /// Starts graceful shutdown, see `Handler::shutdown_gracefully`
+    pub fn shutdown_gracefully(&mut self, deadline: SteadyTime) {
+        self.handler.notified(&mut self.eloop, Notify::Shutdown(deadline));
+        self.handler.check_shutdown(&mut self.eloop);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::Duration;
+
+    use {Async, Scope, Void};
+    use transports::StreamSocket;
+    use transports::stream::{Protocol, Transport, StreamConfig};
+    use testing::{MockStream, MockSocket};
+    use super::Simulation;
+
+    #[derive(Default)]
+    struct Context {
+        received: Vec<(usize, Vec<u8>)>,
+    }
+
+    /// Echoes the input and records it with the id of the connection
+    struct Echo(usize);
+
+    impl Protocol<Context> for Echo {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            None
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.idle_timeout(Duration::seconds(10));
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            let data = trans.input()[..].to_vec();
+            trans.input().consume(data.len());
+            trans.output().extend(&data);
+            scope.received.push((self.0, data));
+            Async::Continue(self, ())
+        }
+    }
+
+    type Sim = Simulation<Context, MockStream<Context, Echo>>;
+
+    fn start(seed: u64) -> (Sim, Vec<MockSocket>) {
+        let mut sim = Sim::new(Context::default(), seed);
+        let socks: Vec<_> = (0..3).map(|_| sim.socket()).collect();
+        for (id, sock) in socks.iter().enumerate() {
+            sim.add_root(MockStream::connecting(sock.clone(), Echo(id),
+                Duration::seconds(1))).unwrap();
+        }
+        sim.run();
+        (sim, socks)
+    }
+
+    fn order(seed: u64) -> Vec<usize> {
+        let (mut sim, socks) = start(seed);
+        for sock in &socks {
+            sock.input(b"ping");
+        }
+        sim.run();
+        for sock in &socks {
+            assert_eq!(sock.output(), b"ping");
+        }
+        sim.context().received.iter().map(|&(id, _)| id).collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(order(7), order(7));
+        let orders: Vec<_> = (0..20).map(order).collect();
+        assert!(orders.iter().any(|x| x != &orders[0]));
+    }
+
+    #[test]
+    fn virtual_time() {
+        let (mut sim, socks) = start(1);
+        sim.advance(Duration::seconds(5));
+        socks[0].input(b"x");
+        sim.run();
+        sim.advance(Duration::milliseconds(9999));
+        assert_eq!(sim.handler().errors(), 2);
+        socks[1].input(b"late");
+        sim.run();
+        assert_eq!(sim.context().received.len(), 1);
+        sim.advance(Duration::milliseconds(5001));
+        assert_eq!(sim.handler().errors(), 3);
+    }
+
+    #[test]
+    fn write_readiness() {
+        let (mut sim, socks) = start(3);
+        socks[1].write_limit(Some(0));
+        socks[1].input(b"hello");
+        sim.run();
+        assert_eq!(socks[1].output(), b"");
+        socks[1].write_limit(Some(3));
+        sim.run();
+        assert_eq!(socks[1].output(), b"hel");
+        socks[1].write_limit(None);
+        sim.run();
+        assert_eq!(socks[1].output(), b"lo");
+    }
+
+    #[test]
+    fn move_stream() {
+        let mut sim = Sim::new(Context::default(), 11);
+        let sock = sim.socket();
+        let token = sim.add_root(MockStream::connecting(sock.clone(), Echo(0),
+            Duration::seconds(1))).unwrap();
+        sim.run();
+        sock.write_limit(Some(2));
+        sock.input(b"hello");
+        sim.run();
+        assert_eq!(sock.output(), b"he");
+        let stream = sim.detach(token).unwrap();
+        assert!(!sock.is_registered());
+        sock.write_limit(None);
+        sim.run();
+        assert_eq!(sock.output(), b"");
+        sim.mailbox().send(stream).ok().unwrap();
+        sim.run();
+        assert_eq!(sock.output(), b"llo");
+        assert_eq!(sim.handler().errors(), 0);
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let (mut sim, socks) = start(5);
+        socks[0].write_limit(Some(0));
+        socks[0].input(b"pending");
+        sim.run();
+        let deadline = sim.now() + Duration::seconds(1);
+        sim.shutdown_gracefully(deadline);
+        sim.run();
+        assert!(!sim.is_shut_down());
+        socks[0].write_limit(None);
+        sim.run();
+        assert_eq!(socks[0].output(), b"pending");
+        assert!(sim.is_shut_down());
+        let summary = sim.handler().shutdown_summary().unwrap();
+        assert_eq!((summary.finished, summary.dropped), (3, 0));
+        assert_eq!(summary.elapsed, Duration::zero());
+    }
+}
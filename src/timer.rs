@@ -0,0 +1,368 @@
+//! Timers of the state machines
+//!
+//! All the timers of the main loop are kept in the `Wheel` owned by the
+//! `Handler`. Only a single mio timeout is used to wake up the loop when
+//! the earliest timer expires.
+use std::cmp::{min, max};
+use std::collections::BTreeMap;
+
+use time::{SteadyTime, Duration};
+use mio::Token;
+
+
+/// The timer which fired, passed to `EventMachine::timeout`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Timer {
+    /// Deadline returned as `Async::Timeout` or set by `Scope::set_timeout`
+    Deadline,
+    /// Timer set by `Scope::set_timer` with this id
+    Id(u64),
+}
+
+/// All the timers of a single state machine
+///
+/// Only the earliest one is scheduled in the timer wheel.
+pub struct Timers {
+    /// Deadline of the current state, replaced at each transition
+    deadline: Option<SteadyTime>,
+    /// Deadline set by `Scope::set_timeout` in the current transition
+    requested: Option<SteadyTime>,
+    /// Timers set by `Scope::set_timer`, kept until fired or cleared
+    keyed: BTreeMap<u64, SteadyTime>,
+    /// The deadline and the key of the timer scheduled in the wheel
+    scheduled: Option<(SteadyTime, Key)>,
+}
+
+/// Handle of the timer scheduled in the `Wheel`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key(usize);
+
+struct Node {
+    token: Token,
+    generation: u64,
+    tick: u64,
+    prev: Option<usize>,
+    /// Next node in the slot, or in the free list for unused nodes
+    next: Option<usize>,
+}
+
+/// Hashed timer wheel with millisecond resolution
+///
+/// Each slot is a doubly-linked list of the timers, so removing a timer
+/// from its slot is O(1). The ordered count of timers per tick gives the
+/// next wakeup without scanning the slots. Timers expiring at the same
+/// millisecond share the slot and are fired at a single wakeup of the
+/// loop. Timers further than the size of the wheel stay in the slot for
+/// several turns.
+pub struct Wheel {
+    start: SteadyTime,
+    /// The first tick which is not processed yet
+    current: u64,
+    /// Number of timers expiring at each tick
+    ticks: BTreeMap<u64, usize>,
+    slots: Vec<Option<usize>>,
+    nodes: Vec<Node>,
+    free: Option<usize>,
+    len: usize,
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            deadline: None,
+            requested: None,
+            keyed: BTreeMap::new(),
+            scheduled: None,
+        }
+    }
+    /// Sets the timer `id`, returns the deadline it replaces
+    pub fn set(&mut self, id: u64, deadline: SteadyTime)
+        -> Option<SteadyTime>
+    {
+        self.keyed.insert(id, deadline)
+    }
+    /// Removes the timer `id`, returns its deadline if it was set
+    pub fn clear(&mut self, id: u64) -> Option<SteadyTime> {
+        self.keyed.remove(&id)
+    }
+    /// Returns the deadline of the timer `id`
+    pub fn get(&self, id: u64) -> Option<SteadyTime> {
+        self.keyed.get(&id).cloned()
+    }
+    pub(crate) fn request(&mut self, deadline: Option<SteadyTime>) {
+        self.requested = deadline;
+    }
+    /// Sets the deadline of the new state
+    ///
+    /// The earlier of `returned` in `Async::Timeout` and the one requested
+    /// through the `Scope` is used.
+    pub(crate) fn transition(&mut self, returned: Option<SteadyTime>) {
+        let requested = self.requested.take();
+        self.deadline = match (returned, requested) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+    fn earliest(&self) -> Option<SteadyTime> {
+        let keyed = self.keyed.values().min().cloned();
+        match (self.deadline, keyed) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+    /// Called when the timer `key` expires in the wheel
+    ///
+    /// Removes and returns the timer which is expired by `now`. The
+    /// `Deadline` goes first, keyed timers in the order of their deadlines.
+    pub(crate) fn fired(&mut self, key: Key, now: SteadyTime)
+        -> Option<Timer>
+    {
+        match self.scheduled {
+            Some((_, k)) if k == key => self.scheduled = None,
+            _ => return None,
+        }
+        if self.deadline.map_or(false, |dl| dl <= now) {
+            self.deadline = None;
+            return Some(Timer::Deadline);
+        }
+        let id = self.keyed.iter()
+            .filter(|&(_, &dl)| dl <= now)
+            .min_by_key(|&(_, &dl)| dl)
+            .map(|(&id, _)| id)?;
+        self.keyed.remove(&id);
+        Some(Timer::Id(id))
+    }
+    /// Schedules the earliest timer in the wheel, if it has changed
+    pub(crate) fn schedule(&mut self, wheel: &mut Wheel,
+        token: Token, generation: u64)
+    {
+        let earliest = self.earliest();
+        if self.scheduled.as_ref().map(|&(dl, _)| dl) == earliest {
+            return;
+        }
+        self.cancel(wheel);
+        self.scheduled = earliest
+            .map(|dl| (dl, wheel.insert(dl, token, generation)));
+    }
+    /// Removes the timer from the wheel
+    pub(crate) fn cancel(&mut self, wheel: &mut Wheel) {
+        if let Some((_, key)) = self.scheduled.take() {
+            wheel.cancel(key);
+        }
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Timers {
+        Timers::new()
+    }
+}
+
+impl Wheel {
+    /// Creates the wheel with `slots` milliseconds in one turn
+    pub fn new(slots: usize, now: SteadyTime) -> Wheel {
+        Wheel {
+            start: now,
+            current: 0,
+            ticks: BTreeMap::new(),
+            slots: vec![None; max(slots, 1)],
+            nodes: Vec::new(),
+            free: None,
+            len: 0,
+        }
+    }
+    /// Number of timers in the wheel
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Returns the time when the loop needs to wake up to process timers
+    ///
+    /// It's the deadline of the earliest timer, rounded up to milliseconds.
+    pub fn next_wakeup(&self) -> Option<SteadyTime> {
+        self.ticks.keys().next()
+        .map(|&tick| self.start + Duration::milliseconds(tick as i64))
+    }
+    /// Tick of the deadline, rounded up so timers never fire early
+    fn tick_of(&self, deadline: SteadyTime) -> u64 {
+        let us = (deadline - self.start).num_microseconds()
+            .unwrap_or(i64::MAX);
+        max(us.saturating_add(999) / 1000, 0) as u64
+    }
+    pub fn insert(&mut self, deadline: SteadyTime,
+        token: Token, generation: u64)
+        -> Key
+    {
+        // Expired timers go into the first slot which is not processed yet
+        let tick = max(self.tick_of(deadline), self.current);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        let node = Node {
+            token,
+            generation,
+            tick,
+            prev: None,
+            next: self.slots[slot],
+        };
+        let index = match self.free {
+            Some(index) => {
+                self.free = self.nodes[index].next;
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        if let Some(head) = self.slots[slot] {
+            self.nodes[head].prev = Some(index);
+        }
+        self.slots[slot] = Some(index);
+        *self.ticks.entry(tick).or_insert(0) += 1;
+        self.len += 1;
+        Key(index)
+    }
+    pub fn cancel(&mut self, key: Key) {
+        self.unlink(key.0);
+    }
+    fn unlink(&mut self, index: usize) {
+        let (prev, next, tick) = {
+            let node = &self.nodes[index];
+            (node.prev, node.next, node.tick)
+        };
+        match prev {
+            Some(prev) => self.nodes[prev].next = next,
+            None => {
+                let slot = (tick % self.slots.len() as u64) as usize;
+                self.slots[slot] = next;
+            }
+        }
+        if let Some(next) = next {
+            self.nodes[next].prev = prev;
+        }
+        let left = {
+            let count = self.ticks.get_mut(&tick)
+                .expect("every timer is counted");
+            *count -= 1;
+            *count
+        };
+        if left == 0 {
+            self.ticks.remove(&tick);
+        }
+        self.nodes[index].prev = None;
+        self.nodes[index].next = self.free;
+        self.free = Some(index);
+        self.len -= 1;
+    }
+    /// Removes all the timers expired by `now` and appends them to `result`
+    pub fn expire(&mut self, now: SteadyTime,
+        result: &mut Vec<(Key, Token, u64)>)
+    {
+        let now_tick = match (now - self.start).num_microseconds() {
+            Some(us) if us < 0 => return,
+            Some(us) => (us / 1000) as u64,
+            None => return,
+        };
+        if now_tick < self.current {
+            return;
+        }
+        let size = self.slots.len() as u64;
+        let turn = min(now_tick - self.current + 1, size);
+        for tick in self.current..self.current + turn {
+            let mut cur = self.slots[(tick % size) as usize];
+            while let Some(index) = cur {
+                cur = self.nodes[index].next;
+                let node = &self.nodes[index];
+                if node.tick <= now_tick {
+                    result.push((Key(index), node.token, node.generation));
+                    self.unlink(index);
+                }
+            }
+        }
+        self.current = now_tick + 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::{SteadyTime, Duration};
+    use mio::Token;
+    use super::Wheel;
+
+    fn ms(x: i64) -> Duration {
+        Duration::milliseconds(x)
+    }
+
+    #[test]
+    fn expire_in_order() {
+        let start = SteadyTime::now();
+        let mut wheel = Wheel::new(16, start);
+        wheel.insert(start + ms(5), Token(1), 0);
+        let cancelled = wheel.insert(start + ms(3), Token(2), 0);
+        wheel.insert(start + ms(40), Token(3), 0);  // after two turns
+        wheel.insert(start + ms(5), Token(4), 0);
+        wheel.cancel(cancelled);
+        assert_eq!(wheel.len(), 3);
+        assert_eq!(wheel.next_wakeup(), Some(start + ms(5)));
+
+        let mut fired = Vec::new();
+        wheel.expire(start + ms(4), &mut fired);
+        assert!(fired.is_empty());
+        assert_eq!(wheel.next_wakeup(), Some(start + ms(5)));
+        wheel.expire(start + ms(5), &mut fired);
+        let tokens: Vec<_> = fired.iter().map(|x| x.1).collect();
+        assert_eq!(tokens, vec![Token(4), Token(1)]);
+        assert_eq!(wheel.next_wakeup(), Some(start + ms(40)));
+        fired.clear();
+        wheel.expire(start + ms(39), &mut fired);
+        assert!(fired.is_empty());
+        wheel.expire(start + ms(100), &mut fired);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].1, Token(3));
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_wakeup(), None);
+    }
+
+    #[test]
+    fn next_wakeup_skips_later_turns() {
+        let start = SteadyTime::now();
+        let mut wheel = Wheel::new(16, start);
+        // Slot of the later timer comes first in the turn
+        wheel.insert(start + ms(20), Token(1), 0);
+        wheel.insert(start + ms(10), Token(2), 0);
+        let mut fired = Vec::new();
+        wheel.expire(start + ms(1), &mut fired);
+        assert!(fired.is_empty());
+        assert_eq!(wheel.next_wakeup(), Some(start + ms(10)));
+        wheel.expire(start + ms(10), &mut fired);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(wheel.next_wakeup(), Some(start + ms(20)));
+    }
+
+    #[test]
+    fn never_early() {
+        let start = SteadyTime::now();
+        let mut wheel = Wheel::new(16, start);
+        let deadline = start + Duration::microseconds(2500);
+        wheel.insert(deadline, Token(1), 0);
+        let mut fired = Vec::new();
+        wheel.expire(start + Duration::microseconds(2999), &mut fired);
+        assert!(fired.is_empty());
+        wheel.expire(start + ms(3), &mut fired);
+        assert_eq!(fired.len(), 1);
+    }
+
+    #[test]
+    fn reuse_nodes() {
+        let start = SteadyTime::now();
+        let mut wheel = Wheel::new(1024, start);
+        for i in 0..100_000 {
+            let key = wheel.insert(start + ms(i % 5000), Token(1), 0);
+            wheel.cancel(key);
+        }
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.nodes.len(), 1);
+    }
+}
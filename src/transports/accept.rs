@@ -1,22 +1,26 @@
 use std::marker::PhantomData;
 
 use mio::TryAccept;
-use mio::{EventSet, Handler, PollOpt, Evented};
+use mio::{EventSet, PollOpt, Evented};
 
-use {Async, EventMachine};
-use handler::Registrator;
+use {Async, EventMachine, Scope, Error, Timer};
+use handler::{Registrator, Abort};
 
 pub enum Serve<C, S, M>
     where
         M: Init<S::Output, C>, M: EventMachine<C>,
         S: TryAccept, S: Evented,
 {
-    Accept(S, PhantomData<*const C>),
+    Accept(S, PhantomData<fn() -> C>),
     Connection(M),
 }
 
 pub trait Init<T, C>: Sized {
-    fn accept(conn: T, context: &mut C) -> Option<Self>;
+    fn accept(conn: T, scope: &mut Scope<C, Self>) -> Option<Self>;
+    /// Called when accepting or adding the connection to the loop fails
+    fn accept_failed(err: Error, _scope: &mut Scope<C, Self>) {
+        error!("Error accepting connection: {}", err);
+    }
 }
 
 impl<S, M, C> Serve<C, S, M>
@@ -26,66 +30,157 @@ impl<S, M, C> Serve<C, S, M>
     pub fn new(sock: S) -> Self {
         Serve::Accept(sock, PhantomData)
     }
+    fn accept(sock: S, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        use self::Serve::*;
+        let new_machine = match sock.accept() {
+            Ok(Some(child)) => {
+                scope.wrap(Connection,
+                    |s| <M as Init<_, _>>::accept(child, s))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                let err = Error::Accept(e);
+                scope.wrap(Connection,
+                    |s| <M as Init<_, _>>::accept_failed(err, s));
+                None
+            }
+        };
+        Async::Continue(Accept(sock, PhantomData), new_machine.map(Connection))
+    }
+    /// Accepts all the pending connections
+    ///
+    /// Wakeups are lost when the notification queue is full, so a single
+    /// one must pick up all the connections handed off so far.
+    fn accept_all(sock: S, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        use self::Serve::*;
+        loop {
+            match sock.accept() {
+                Ok(Some(child)) => {
+                    let conn = scope.wrap(Connection,
+                        |s| <M as Init<_, _>>::accept(child, s));
+                    if let Some(conn) = conn {
+                        scope.spawn(Connection(conn));
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let err = Error::Accept(e);
+                    scope.wrap(Connection,
+                        |s| <M as Init<_, _>>::accept_failed(err, s));
+                    break;
+                }
+            }
+        }
+        Async::Continue(Accept(sock, PhantomData), None)
+    }
 }
 
 
 impl<C, S, M: EventMachine<C>> EventMachine<C> for Serve<C, S, M>
     where S: TryAccept, S: Evented, M: Init<S::Output, C>
 {
-    fn ready(self, evset: EventSet, context: &mut C)
+    fn ready(self, evset: EventSet, scope: &mut Scope<C, Self>)
         -> Async<Self, Option<Self>>
     {
         use self::Serve::*;
         match self {
-            Accept(sock, _) => {
-                let new_machine = match sock.accept() {
-                    Ok(Some(child)) => {
-                        <M as Init<_, _>>::accept(child, context)
-                    }
-                    Ok(None) => None,
-                    Err(e) => {
-                        error!("Error on socket accept: {}", e);
-                        None
-                    }
-                };
-                Async::Continue(Accept(sock, PhantomData),
-                    new_machine.map(Connection))
-            }
+            Accept(sock, _) => Serve::accept(sock, scope),
             Connection(c) => {
-                c.ready(evset, context)
+                scope.wrap(Connection, |s| c.ready(evset, s))
                     .map(Connection).map_result(|x| x.map(Connection))
             }
         }
     }
-    fn register(self, reg: &mut Registrator) -> Async<Self, ()> {
+    fn register(self, reg: &mut dyn Registrator) -> Async<Self, ()> {
         use self::Serve::*;
         match self {
             Accept(s, _) => {
-                reg.register(&s, EventSet::readable(), PollOpt::level());
-                Async::Continue(Accept(s, PhantomData), ())
+                match reg.register(&s, EventSet::readable(), PollOpt::level()) {
+                    Ok(()) => Async::Continue(Accept(s, PhantomData), ()),
+                    Err(_) => Async::Stop,
+                }
             }
             Connection(c) => c.register(reg).map(Connection),
         }
     }
-    fn timeout(self, context: &mut C) -> Async<Self, Option<Self>> {
+    fn reregister(&mut self, reg: &mut dyn Registrator) {
+        if let Serve::Connection(ref mut c) = *self {
+            c.reregister(reg);
+        }
+    }
+    fn detach(&mut self, reg: &mut dyn Registrator) {
+        match *self {
+            Serve::Accept(ref s, _) => {
+                reg.deregister(s).ok();
+            }
+            Serve::Connection(ref mut c) => c.detach(reg),
+        }
+    }
+    fn timeout(self, timer: Timer, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
         use self::Serve::*;
         match self {
             me @ Accept(_, _) => Async::Continue(me, None),
             Connection(c) => {
-                c.timeout(context)
+                scope.wrap(Connection, |s| c.timeout(timer, s))
                     .map(Connection).map_result(|x| x.map(Connection))
             }
         }
     }
 
-    fn wakeup(self, context: &mut C) -> Async<Self, Option<Self>> {
+    /// Accepts connections, this is how `runtime::Handoff` delivers them
+    fn wakeup(self, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
         use self::Serve::*;
         match self {
-            me @ Accept(_, _) => Async::Continue(me, None),
+            Accept(sock, _) => Serve::accept_all(sock, scope),
             Connection(c) => {
-                c.wakeup(context)
+                scope.wrap(Connection, |s| c.wakeup(s))
+                    .map(Connection).map_result(|x| x.map(Connection))
+            }
+        }
+    }
+
+    /// Stops accepting and shuts down the connection
+    fn shutdown(self, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        use self::Serve::*;
+        match self {
+            Accept(_, _) => Async::Stop,
+            Connection(c) => {
+                scope.wrap(Connection, |s| c.shutdown(s))
+                    .map(Connection).map_result(|x| x.map(Connection))
+            }
+        }
+    }
+
+    fn spawn_failed(self, child: Self, reason: Abort,
+        scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        use self::Serve::*;
+        match (self, child) {
+            (me @ Accept(_, _), _) => {
+                // Dropping the connection closes the socket, so the client
+                // is notified immediately instead of waiting in the backlog
+                scope.wrap(Connection, |s| {
+                    <M as Init<_, _>>::accept_failed(Error::Register(reason), s)
+                });
+                Async::Continue(me, None)
+            }
+            (Connection(c), Connection(child)) => {
+                scope.wrap(Connection,
+                    |s| c.spawn_failed(child, reason, s))
                     .map(Connection).map_result(|x| x.map(Connection))
             }
+            (me @ Connection(_), Accept(_, _)) => Async::Continue(me, None),
         }
     }
 }
@@ -0,0 +1,354 @@
+use std::cmp::max;
+use std::io::ErrorKind::Interrupted;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::collections::VecDeque;
+
+use mio::{EventSet, PollOpt};
+use mio::udp::UdpSocket;
+use mio::buf::{SliceBuf, MutSliceBuf, MutBuf};
+
+use handler::{Abort, Registrator};
+use super::Budget;
+use {Async, EventMachine, Scope, Void, Error, Timer};
+
+/// Number of packets queued for sending used by `Datagram::new`
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+/// Maximum size of the UDP packet
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// Bounded queue of outgoing packets
+///
+/// The packets are sent when socket is writable, after the protocol action
+/// returns.
+pub struct Queue {
+    packets: VecDeque<(SocketAddr, Vec<u8>)>,
+    capacity: usize,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    queue: Queue,
+    buf: Vec<u8>,
+    writable: bool,
+    /// Socket may have packets, read until it returns `WouldBlock`
+    readable: bool,
+}
+
+pub struct Datagram<C, P: DatagramProtocol<C>>
+    (Inner, P, PhantomData<fn() -> C>);
+
+pub trait DatagramProtocol<C>: Sized {
+    /// Called for every packet received on the socket
+    fn packet_received(self, addr: &SocketAddr, data: &[u8],
+        queue: &mut Queue, scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>;
+    /// Called when sending or receiving a packet fails
+    ///
+    /// Errors are not fatal for datagram socket (e.g. `Error::Read` with
+    /// `ConnectionRefused` when the peer of the previous packet is not
+    /// listening), so the default is to continue.
+    fn error_happened(self, _err: &Error, _queue: &mut Queue,
+        _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+    fn timeout(self, _timer: Timer, _queue: &mut Queue,
+        _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+    fn wakeup(self, _queue: &mut Queue, _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+}
+
+impl Queue {
+    pub fn new(capacity: usize) -> Queue {
+        Queue {
+            packets: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+    /// Queues the packet for sending
+    ///
+    /// Returns the packet back if the queue is full
+    pub fn send(&mut self, addr: SocketAddr, data: Vec<u8>)
+        -> Result<(), (SocketAddr, Vec<u8>)>
+    {
+        if self.is_full() {
+            return Err((addr, data));
+        }
+        self.packets.push_back((addr, data));
+        Ok(())
+    }
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+    pub fn is_full(&self) -> bool {
+        self.packets.len() >= self.capacity
+    }
+}
+
+impl<C, P: DatagramProtocol<C>> Datagram<C, P> {
+    pub fn new(sock: UdpSocket, protocol: P) -> Self {
+        Datagram::with_capacity(sock, protocol, DEFAULT_QUEUE_CAPACITY)
+    }
+    /// Creates datagram machine with the specified outgoing queue capacity
+    pub fn with_capacity(sock: UdpSocket, protocol: P, capacity: usize)
+        -> Self
+    {
+        Datagram(Inner {
+            socket: sock,
+            queue: Queue::new(capacity),
+            buf: vec![0; MAX_PACKET_SIZE],
+            writable: true,
+            readable: false,
+        }, protocol, PhantomData)
+    }
+    fn action(self, evset: EventSet, scope: &mut Scope<C, Void>)
+        -> Async<Self, Option<Self>>
+    {
+        let Datagram(mut dgram, fsm, _) = self;
+        let mut monad = Async::Continue(fsm, ());
+        if evset.is_writable() {
+            dgram.writable = true;
+        }
+        if evset.is_readable() {
+            dgram.readable = true;
+        }
+        // Like with streams, readiness is kept when budget is exhausted and
+        // the machine continues on the next iteration
+        let mut budget = Budget::new(scope.io_budget());
+        while dgram.readable {
+            if budget.read == 0 && budget.reschedule(scope) {
+                break;
+            }
+            let res = {
+                let mut buf = MutSliceBuf::wrap(&mut dgram.buf[..]);
+                dgram.socket.recv_from(&mut buf)
+                    .map(|x| x.map(|addr| (addr, buf.remaining())))
+            };
+            match res {
+                Ok(Some((addr, remaining))) => {
+                    let len = MAX_PACKET_SIZE - remaining;
+                    // Empty packets are not free to process either
+                    budget.read = budget.read.saturating_sub(max(len, 1));
+                    let Inner { ref buf, ref mut queue, .. } = dgram;
+                    monad = async_try!(monad.and_then(|f| {
+                        f.packet_received(&addr, &buf[..len], queue, scope)
+                    }));
+                }
+                Ok(None) => dgram.readable = false,
+                Err(ref e) if e.kind() == Interrupted =>  { continue; }
+                Err(e) => {
+                    let queue = &mut dgram.queue;
+                    monad = async_try!(monad.and_then(|f| {
+                        f.error_happened(&Error::read(e), queue, scope)
+                    }));
+                    // The error may repeat, so give other machines a chance.
+                    // If the queue is full, reading continues as usual.
+                    budget.read = 0;
+                }
+            }
+        }
+        let monad = dgram.flush(monad, scope);
+        monad
+        .map(|fsm| Datagram(dgram, fsm, PhantomData))
+        .map_result(|()| None)
+    }
+}
+
+impl Inner {
+    /// Sends queued packets while socket is writable
+    fn flush<C, P>(&mut self, mut monad: Async<P, ()>,
+        scope: &mut Scope<C, Void>)
+        -> Async<P, ()>
+        where P: DatagramProtocol<C>
+    {
+        while self.writable && !self.queue.is_empty() {
+            let res = {
+                let (ref addr, ref data) = self.queue.packets[0];
+                self.socket.send_to(&mut SliceBuf::wrap(data), addr)
+            };
+            match res {
+                Ok(Some(())) => {
+                    self.queue.packets.pop_front();
+                }
+                Ok(None) => self.writable = false,
+                Err(ref e) if e.kind() == Interrupted =>  { continue; }
+                Err(e) => {
+                    // Packet is dropped, so error doesn't repeat forever
+                    self.queue.packets.pop_front();
+                    let queue = &mut self.queue;
+                    monad = async_try!(monad.and_then(|f| {
+                        f.error_happened(&Error::write(e), queue, scope)
+                    }));
+                }
+            }
+        }
+        monad
+    }
+}
+
+impl<C, P: DatagramProtocol<C>> EventMachine<C> for Datagram<C, P> {
+    fn ready(self, evset: EventSet, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        scope.wrap(Void::unreachable, |scope| self.action(evset, scope))
+    }
+
+    fn register(self, reg: &mut dyn Registrator) -> Async<Self, ()> {
+        match reg.register(&self.0.socket,
+            EventSet::readable() | EventSet::writable(), PollOpt::edge())
+        {
+            Ok(()) => Async::Continue(self, ()),
+            Err(_) => Async::Error(Error::Register(Abort::RegisterFailed)),
+        }
+    }
+
+    fn detach(&mut self, reg: &mut dyn Registrator) {
+        reg.deregister(&self.0.socket).ok();
+    }
+
+    fn timeout(self, timer: Timer, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        let Datagram(mut dgram, fsm, _) = self;
+        scope.wrap(Void::unreachable, |s| {
+            let monad = fsm.timeout(timer, &mut dgram.queue, s);
+            dgram.flush(monad, s)
+        })
+        .map(|fsm| Datagram(dgram, fsm, PhantomData))
+        .map_result(|()| None)
+    }
+
+    fn wakeup(self, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        let Datagram(mut dgram, fsm, _) = self;
+        scope.wrap(Void::unreachable, |s| {
+            let monad = fsm.wakeup(&mut dgram.queue, s);
+            dgram.flush(monad, s)
+        })
+        .map(|fsm| Datagram(dgram, fsm, PhantomData))
+        .map_result(|()| None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{SocketAddr, UdpSocket as StdSocket};
+
+    use mio::EventLoop;
+    use mio::udp::UdpSocket;
+
+    use {Async, Handler, HandlerConfig, Scope, Void};
+    use super::{Datagram, DatagramProtocol, Queue};
+
+    struct Echo;
+
+    impl DatagramProtocol<()> for Echo {
+        fn packet_received(self, addr: &SocketAddr, data: &[u8],
+            queue: &mut Queue, scope: &mut Scope<(), Void>)
+            -> Async<Self, ()>
+        {
+            queue.send(*addr, data.to_vec()).unwrap();
+            scope.shutdown_loop();
+            Async::Continue(self, ())
+        }
+    }
+
+    /// Counts the received packets
+    struct Counter;
+
+    impl DatagramProtocol<usize> for Counter {
+        fn packet_received(self, _addr: &SocketAddr, _data: &[u8],
+            _queue: &mut Queue, scope: &mut Scope<usize, Void>)
+            -> Async<Self, ()>
+        {
+            **scope += 1;
+            Async::Continue(self, ())
+        }
+    }
+
+    /// Returns the socket with `num` packets of four bytes queued
+    fn flooded(num: usize) -> UdpSocket {
+        let sock = UdpSocket::bound(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let client = StdSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..num {
+            client.send_to(b"ping", addr).unwrap();
+        }
+        sock
+    }
+
+    #[test]
+    fn io_budget() {
+        const PACKETS: usize = 10;
+        let mut config = HandlerConfig::new();
+        config.io_budget(4);
+        let mut eloop = config.event_loop().unwrap();
+        let mut handler = Handler::configured(0, &config);
+        handler.add_root(&mut eloop, Datagram::new(flooded(PACKETS), Counter))
+            .unwrap();
+        while *handler.context() < PACKETS {
+            let before = *handler.context();
+            eloop.run_once(&mut handler).unwrap();
+            // One packet on the edge event and one on the rescheduled event
+            assert!(*handler.context() - before <= 2);
+        }
+    }
+
+    #[test]
+    fn reschedule_failed() {
+        const PACKETS: usize = 10;
+        let mut config = HandlerConfig::new();
+        config.io_budget(4).notify_capacity(1);
+        let mut eloop = config.event_loop().unwrap();
+        let mut handler = Handler::configured(0, &config);
+        let tok = handler.add_root(&mut eloop,
+            Datagram::new(flooded(PACKETS), Counter)).unwrap();
+        let notifier = handler.notifier(&eloop, tok).unwrap();
+        while notifier.wakeup().is_ok() {}
+        eloop.run_once(&mut handler).unwrap();
+        // Budget is ignored, otherwise the packets are left unread
+        assert_eq!(*handler.context(), PACKETS);
+    }
+
+    #[test]
+    fn queue_full() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut queue = Queue::new(1);
+        queue.send(addr, b"one".to_vec()).unwrap();
+        assert!(queue.is_full());
+        assert_eq!(queue.send(addr, b"two".to_vec()),
+            Err((addr, b"two".to_vec())));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn echo() {
+        let sock = UdpSocket::bound(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let client = StdSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", addr).unwrap();
+
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new((), &mut eloop);
+        handler.add_root(&mut eloop, Datagram::new(sock, Echo)).unwrap();
+        eloop.run(&mut handler).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, peer) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(peer, addr);
+    }
+}
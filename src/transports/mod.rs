@@ -1,10 +1,122 @@
+use std::io;
+use std::mem::size_of;
 use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
 
+use libc;
 use mio::Evented;
+use mio::tcp::{TcpSocket, TcpListener};
+
+use {Scope, Void};
 
 pub mod stream;
 pub mod accept;
+pub mod datagram;
+pub mod unix;
 
 pub trait StreamSocket: Read + Write + Evented {}
 impl<T> StreamSocket for T where T: Read, T: Write, T: Evented {}
 
+/// Bytes the machine may still read and write in the current event
+struct Budget {
+    read: usize,
+    write: usize,
+    rescheduled: bool,
+}
+
+impl Budget {
+    fn new(bytes: usize) -> Budget {
+        Budget {
+            read: bytes,
+            write: bytes,
+            rescheduled: false,
+        }
+    }
+    /// Schedules machine to continue on the next loop iteration
+    ///
+    /// Returns `false` if that's impossible, the budget is ignored then.
+    fn reschedule<C>(&mut self, scope: &mut Scope<C, Void>) -> bool {
+        if self.rescheduled {
+            return true;
+        }
+        match scope.reschedule() {
+            Ok(()) => {
+                self.rescheduled = true;
+                true
+            }
+            Err(e) => {
+                warn!("Can't reschedule machine: {:?}. Ignoring io budget", e);
+                self.read = usize::MAX;
+                self.write = usize::MAX;
+                false
+            }
+        }
+    }
+}
+
+/// Returns and clears pending error of the socket (`SO_ERROR`)
+///
+/// This is how the result of non-blocking `connect` is checked.
+pub fn take_socket_error<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    let mut err: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR,
+            &mut err as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+        Err(io::Error::last_os_error())
+    } else if err != 0 {
+        Err(io::Error::from_raw_os_error(err))
+    } else {
+        Ok(())
+    }
+}
+
+/// Binds the listening socket with `SO_REUSEPORT`
+///
+/// Each loop of the `runtime::Runtime` may bind its own listener to the
+/// same address, and the kernel balances connections between them.
+pub fn bind_reuse_port(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let sock = match *addr {
+        SocketAddr::V4(_) => TcpSocket::v4()?,
+        SocketAddr::V6(_) => TcpSocket::v6()?,
+    };
+    sock.set_reuseaddr(true)?;
+    let enable: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    sock.bind(addr)?;
+    sock.listen(1024)
+}
+
+/// Shuts down the write side of the socket, peer receives EOF
+pub(crate) fn shutdown_write<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    let res = unsafe { libc::shutdown(sock.as_raw_fd(), libc::SHUT_WR) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets zero `SO_LINGER`, so that closing the socket sends RST
+pub(crate) fn reset_on_close<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let res = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            size_of::<libc::linger>() as libc::socklen_t)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
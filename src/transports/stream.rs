@@ -1,51 +1,260 @@
+use std::cmp::max;
 use std::marker::PhantomData;
 use std::io;
-use std::io::ErrorKind::{WouldBlock, Interrupted};
+use std::io::ErrorKind::{WouldBlock, Interrupted, WriteZero};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::os::unix::io::AsRawFd;
 
 use netbuf::Buf;
-use time::SteadyTime;
+use time::{SteadyTime, Duration};
 use mio::{EventSet, PollOpt};
+use mio::tcp::TcpStream;
+use mio::unix::UnixStream;
 
 use super::StreamSocket as Socket;
+use super::{take_socket_error, shutdown_write, reset_on_close, Budget};
 use super::accept::Init;
-use handler::{Registrator};
-use {Async, EventMachine};
+use handler::{Abort, Registrator};
+use {Async, EventMachine, Scope, Void, Error, Timer};
 
 pub struct Timeout(pub SteadyTime);
 
+/// Connect timeout used by `Stream::connect`
+pub const DEFAULT_CONNECT_TIMEOUT_MS: i64 = 10_000;
+/// Default size of input buffer when stream stops reading
+pub const DEFAULT_INPUT_HIGH_WATERMARK: usize = 1 << 20;
+
+/// Buffer limits and timeouts of a stream, returned by `Protocol::config()`
+///
+/// All the setters return `&mut Self` so they can be chained.
+#[derive(Clone, Debug)]
+pub struct StreamConfig {
+    input_high_watermark: usize,
+    output_low_watermark: usize,
+    output_limit: Option<usize>,
+    io_budget: Option<usize>,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+/// Closing of the connection requested by protocol via `Transport`
+///
+/// Ordered by priority, i.e. `abort()` overrides `close_after_flush()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Request {
+    ShutdownWrite,
+    CloseAfterFlush,
+    Abort,
+    Reset,
+}
+
+/// Why the connection was closed by the protocol, see `Protocol::closed`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Closed by `Transport::close_after_flush()` when all data is sent
+    Flushed,
+    /// Closed by `Transport::abort()` discarding unsent data
+    Aborted,
+    /// Closed by `Transport::reset()`, peer receives RST
+    Reset,
+}
+
+/// Timeout of the stream itself, see `Protocol::stream_timeout`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// Nothing is read or written for `StreamConfig::idle_timeout`
+    Idle,
+    /// Nothing is read for `StreamConfig::read_timeout`
+    Read,
+    /// No output is sent for `StreamConfig::write_timeout`
+    Write,
+}
+
+const TIMERS: [TimeoutKind; 3] =
+    [TimeoutKind::Idle, TimeoutKind::Read, TimeoutKind::Write];
+
 struct Inner<S: Socket> {
     socket: S,
     inbuf: Buf,
     outbuf: Buf,
     writable: bool,
     readable: bool,
+    /// Deadline of the connection attempt, `None` when connected
+    connecting: Option<SteadyTime>,
+    /// Connect timeout, converted to the deadline when registered
+    connect_timeout: Option<Duration>,
+    config: StreamConfig,
+    request: Option<Request>,
+    /// Write side is shut down by `Transport::shutdown_write()`
+    write_shut: bool,
+    /// End of stream is received from peer
+    eof: bool,
+    /// Time of the last read or write
+    last_active: SteadyTime,
+    /// Time of the last read
+    last_read: SteadyTime,
+    /// Time of the last write, or when output became pending
+    last_write: SteadyTime,
+    /// Output buffer was non-empty after the previous event
+    output_pending: bool,
+    /// Deadline returned by the protocol, stream timers are merged into it
+    protocol_deadline: Option<SteadyTime>,
 }
 
 pub struct Stream<C, S: Socket, P: Protocol<C>>
-    (Inner<S>, P, PhantomData<*mut C>);
+    (Inner<S>, P, PhantomData<fn() -> C>);
 
 pub struct Transport<'a> {
     inbuf: &'a mut Buf,
     outbuf: &'a mut Buf,
+    request: Option<Request>,
 }
 
 
 impl<S: Socket> Inner<S> {
-    fn transport(&mut self) -> Transport {
-        Transport {
-            inbuf: &mut self.inbuf,
-            outbuf: &mut self.outbuf,
+    /// Calls protocol action and records close requested by it
+    fn transport<R, F>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Transport) -> R
+    {
+        let (result, request) = {
+            let mut trans = Transport::new(&mut self.inbuf, &mut self.outbuf);
+            let result = f(&mut trans);
+            (result, trans.request)
+        };
+        self.request = max(self.request, request);
+        result
+    }
+    /// Whether the protocol wants more input
+    fn reading(&self) -> bool {
+        self.readable && !self.eof
+            && self.inbuf.len() < self.config.input_high_watermark
+            && self.request <= Some(Request::ShutdownWrite)
+    }
+    /// Deadline of the stream timer of the `kind`, if the timer is active
+    fn timer(&self, kind: TimeoutKind) -> Option<SteadyTime> {
+        if self.connecting.is_some() {
+            return None;
         }
+        let (timeout, since) = match kind {
+            TimeoutKind::Idle => (self.config.idle_timeout, self.last_active),
+            TimeoutKind::Read
+            if self.eof || self.request > Some(Request::ShutdownWrite)
+            => return None,
+            TimeoutKind::Read => (self.config.read_timeout, self.last_read),
+            TimeoutKind::Write if self.outbuf.len() == 0 => return None,
+            TimeoutKind::Write => (self.config.write_timeout, self.last_write),
+        };
+        timeout.map(|t| since + t)
+    }
+    /// Returns stream timer which has expired by `now`
+    fn expired(&self, now: SteadyTime) -> Option<TimeoutKind> {
+        TIMERS.iter().cloned()
+            .find(|&k| self.timer(k).map_or(false, |dl| dl <= now))
+    }
+    /// Starts the timer of the `kind` anew
+    fn restart(&mut self, kind: TimeoutKind, now: SteadyTime) {
+        match kind {
+            TimeoutKind::Idle => self.last_active = now,
+            TimeoutKind::Read => self.last_read = now,
+            TimeoutKind::Write => self.last_write = now,
+        }
+    }
+    /// Earliest of the protocol deadline and all the stream timers
+    fn deadline(&self) -> Option<SteadyTime> {
+        TIMERS.iter().filter_map(|&k| self.timer(k))
+            .chain(self.connecting)
+            .chain(self.protocol_deadline)
+            .min()
+    }
+    /// Writes output buffer while socket is writable
+    fn flush<C, P>(&mut self, mut monad: Async<P, ()>,
+        budget: &mut Budget, scope: &mut Scope<C, Void>)
+        -> Async<P, ()>
+        where P: Protocol<C>
+    {
+        if self.write_shut || self.request >= Some(Request::Abort) {
+            // Nobody can receive the data anymore
+            let len = self.outbuf.len();
+            self.outbuf.consume(len);
+        }
+        let low = self.config.output_low_watermark;
+        let was_above = self.outbuf.len() > low;
+        while self.writable && self.outbuf.len() > 0 {
+            if budget.write == 0 && budget.reschedule(scope) {
+                break;
+            }
+            match self.outbuf.write_to(&mut self.socket) {
+                Ok(0) => {
+                    let err = Error::Write(
+                        io::Error::new(WriteZero, "write returned zero"));
+                    return Async::Error(err);
+                }
+                Ok(bytes) => {
+                    budget.write = budget.write.saturating_sub(bytes);
+                    self.last_write = scope.now();
+                    self.last_active = scope.now();
+                    monad = async_try!(monad.and_then(|f| {
+                        self.transport(|t| f.data_transferred(t, scope))
+                    }));
+                }
+                Err(ref e) if e.kind() == WouldBlock => {
+                    self.writable = false;
+                    break;
+                }
+                Err(ref e) if e.kind() == Interrupted =>  { continue; }
+                Err(e) => {
+                    let err = Error::write(e);
+                    return Async::Error(err);
+                }
+            }
+        }
+        if was_above && self.outbuf.len() <= low {
+            monad = async_try!(monad.and_then(|f| {
+                self.transport(|t| f.output_drained(t, scope))
+            }));
+        }
+        monad
     }
 }
 
-impl<C, S: Socket, P: Protocol<C>> Init<S, C> for Stream<C, S, P> {
-    fn accept(mut conn: S, context: &mut C) -> Option<Self>
+impl<S: Socket + AsRawFd> Inner<S> {
+    /// Executes close requested by protocol
+    ///
+    /// Returns the reason if connection must be closed now
+    fn close(&mut self) -> Option<CloseReason> {
+        match self.request {
+            None => None,
+            Some(Request::Reset) => {
+                if let Err(e) = reset_on_close(&self.socket) {
+                    debug!("Can't set SO_LINGER: {}", e);
+                }
+                Some(CloseReason::Reset)
+            }
+            Some(Request::Abort) => Some(CloseReason::Aborted),
+            Some(_) if self.outbuf.len() > 0 => None,
+            Some(Request::CloseAfterFlush) => Some(CloseReason::Flushed),
+            Some(Request::ShutdownWrite) => {
+                if !self.write_shut {
+                    if let Err(e) = shutdown_write(&self.socket) {
+                        debug!("Can't shutdown write side: {}", e);
+                    }
+                    self.write_shut = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<C, S, P> Init<S, C> for Stream<C, S, P>
+    where S: Socket + AsRawFd, P: Protocol<C>,
+{
+    fn accept(mut conn: S, scope: &mut Scope<C, Self>) -> Option<Self>
     {
-        let protocol = match Protocol::accepted(&mut conn, context) {
-            Some(x) => x,
-            None => return None
-        };
+        let protocol: P = scope.wrap(Void::unreachable,
+            |s| Protocol::accepted(&mut conn, s))?;
 
         Some(Stream(Inner {
             socket: conn,
@@ -53,152 +262,482 @@ impl<C, S: Socket, P: Protocol<C>> Init<S, C> for Stream<C, S, P> {
             outbuf: Buf::new(),
             readable: false,
             writable: true,   // Accepted socket is immediately writable
+            connecting: None,
+            connect_timeout: None,
+            config: protocol.config(),
+            request: None,
+            write_shut: false,
+            eof: false,
+            last_active: scope.now(),
+            last_read: scope.now(),
+            last_write: scope.now(),
+            output_pending: false,
+            protocol_deadline: None,
         }, protocol, PhantomData))
     }
+    fn accept_failed(err: Error, scope: &mut Scope<C, Self>) {
+        scope.wrap(Void::unreachable, |s| P::accept_failed(err, s))
+    }
+}
+
+impl<C, P: Protocol<C>> Stream<C, TcpStream, P> {
+    /// Starts connecting to the address
+    ///
+    /// The `protocol.connected()` is called when connection is established,
+    /// and `Protocol::error_happened()` if connection fails or doesn't
+    /// succeed in `DEFAULT_CONNECT_TIMEOUT_MS`.
+    pub fn connect(addr: &SocketAddr, protocol: P) -> io::Result<Self> {
+        Stream::connect_timeout(addr, protocol,
+            Duration::milliseconds(DEFAULT_CONNECT_TIMEOUT_MS))
+    }
+    /// Same as `connect` but with the specified connect timeout
+    pub fn connect_timeout(addr: &SocketAddr, protocol: P, timeout: Duration)
+        -> io::Result<Self>
+    {
+        let sock = TcpStream::connect(addr)?;
+        Ok(Stream::connecting(sock, protocol, timeout))
+    }
+}
+
+impl<C, P: Protocol<C>> Stream<C, UnixStream, P> {
+    /// Starts connecting to the unix socket at `path`
+    ///
+    /// Works the same way as `Stream::connect` for TCP
+    pub fn connect_unix<Q: AsRef<Path>>(path: Q, protocol: P)
+        -> io::Result<Self>
+    {
+        let sock = UnixStream::connect(path.as_ref())?;
+        Ok(Stream::connecting(sock, protocol,
+            Duration::milliseconds(DEFAULT_CONNECT_TIMEOUT_MS)))
+    }
 }
 
-impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
-    fn ready(self, evset: EventSet, context: &mut C)
+impl<C, S: Socket + AsRawFd, P: Protocol<C>> Stream<C, S, P> {
+    /// Creates the stream for the socket which is connecting already
+    ///
+    /// `Protocol::connected` is called when the socket becomes writable.
+    /// Useful for sockets created elsewhere, e.g. `testing::MockSocket`
+    /// (with the `testing` feature).
+    pub fn connecting(sock: S, protocol: P, timeout: Duration)
+        -> Self
+    {
+        let now = SteadyTime::now();
+        Stream(Inner {
+            socket: sock,
+            inbuf: Buf::new(),
+            outbuf: Buf::new(),
+            readable: false,
+            writable: false,
+            connecting: Some(now + timeout),
+            connect_timeout: Some(timeout),
+            config: protocol.config(),
+            request: None,
+            write_shut: false,
+            eof: false,
+            last_active: now,
+            last_read: now,
+            last_write: now,
+            output_pending: false,
+            protocol_deadline: None,
+        }, protocol, PhantomData)
+    }
+    /// Merges the stream timers with the deadline returned by protocol
+    ///
+    /// Every action ends here, so this is also where `error_happened` is
+    /// called, both for the errors of the stream and of the protocol.
+    fn arm(ares: Async<Self, Option<Self>>, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        let (mut s, child, deadline) = match ares {
+            Async::Continue(s, child) => (s, child, None),
+            Async::Timeout(s, dl) => (s, None, Some(dl)),
+            Async::Stop => return Async::Stop,
+            Async::Error(e) => {
+                scope.wrap(Void::unreachable, |s| P::error_happened(&e, s));
+                return Async::Error(e);
+            }
+        };
+        let now = scope.now();
+        s.0.protocol_deadline = deadline;
+        // Write timer starts when output appears in the buffer
+        let pending = s.0.outbuf.len() > 0;
+        if pending && !s.0.output_pending {
+            s.0.last_write = now;
+        }
+        s.0.output_pending = pending;
+        match s.0.deadline() {
+            Some(dl) => Async::Timeout(s, dl),
+            None => Async::Continue(s, child),
+        }
+    }
+    fn establish(self, evset: EventSet, scope: &mut Scope<C, Void>)
+        -> Async<Self, Option<Self>>
+    {
+        if !evset.is_writable() && !evset.is_error() && !evset.is_hup() {
+            return Async::Continue(self, None);
+        }
+        let Stream(mut stream, fsm, _) = self;
+        if let Err(e) = take_socket_error(&stream.socket) {
+            return Async::Error(Error::Connect(e));
+        }
+        stream.connecting = None;
+        stream.writable = true;
+        stream.last_active = scope.now();
+        stream.last_read = scope.now();
+        stream.last_write = scope.now();
+        let monad = stream.transport(|t| fsm.connected(t, scope));
+        monad.map(|fsm| Stream(stream, fsm, PhantomData))
+        .and_then(|s| s.action(evset, scope))
+    }
+    fn action(self, evset: EventSet, scope: &mut Scope<C, Void>)
         -> Async<Self, Option<Self>>
     {
         let Stream(mut stream, fsm, _) = self;
         let mut monad = Async::Continue(fsm, ());
-        if evset.is_writable() && stream.outbuf.len() > 0 {
+        if evset.is_writable() {
             stream.writable = true;
-            while stream.outbuf.len() > 0 {
-                match stream.outbuf.write_to(&mut stream.socket) {
-                    Ok(0) => { // Connection closed
-                        monad.done(|fsm| fsm.eof_received(context));
-                        return Async::Stop;
-                    }
-                    Ok(_) => {
-                        monad = async_try!(monad.and_then(|f| {
-                            f.data_transferred(
-                                &mut stream.transport(), context)
-                        }));
-                    }
-                    Err(ref e) if e.kind() == WouldBlock => {
-                        stream.writable = false;
-                        break;
-                    }
-                    Err(ref e) if e.kind() == Interrupted =>  { continue; }
-                    Err(e) => {
-                        monad.done(|fsm| fsm.error_happened(e, context));
-                        return Async::Stop;
-                    }
-                }
-            }
         }
         if evset.is_readable() {
             stream.readable = true;
-            loop {
-                match stream.inbuf.read_from(&mut stream.socket) {
-                    Ok(0) => { // Connection closed
-                        monad.done(|fsm| fsm.eof_received(context));
-                        return Async::Stop;
-                    }
-                    Ok(_) => {
-                        monad = async_try!(monad.and_then(|f| {
-                            f.data_received(
-                                &mut stream.transport(), context)
-                        }));
-                    }
-                    Err(ref e) if e.kind() == WouldBlock => {
-                        stream.readable = false;
-                        break;
-                    }
-                    Err(ref e) if e.kind() == Interrupted =>  { continue; }
-                    Err(e) => {
-                        monad.done(|fsm| fsm.error_happened(e, context));
-                        return Async::Stop;
-                    }
-                }
-            }
         }
-        if stream.writable && stream.outbuf.len() > 0 {
-            while stream.outbuf.len() > 0 {
-                match stream.outbuf.write_to(&mut stream.socket) {
-                    Ok(0) => { // Connection closed
-                        monad.done(|fsm| fsm.eof_received(context));
-                        return Async::Stop;
-                    }
-                    Ok(_) => {
-                        monad = async_try!(monad.and_then(|f| {
-                            f.data_transferred(
-                                &mut stream.transport(), context)
-                        }));
-                    }
-                    Err(ref e) if e.kind() == WouldBlock => {
-                        stream.writable = false;
-                        break;
-                    }
-                    Err(ref e) if e.kind() == Interrupted =>  { continue; }
-                    Err(e) => {
-                        monad.done(|fsm| fsm.error_happened(e, context));
-                        return Async::Stop;
-                    }
+        // When budget is exhausted readiness flags are kept and the stream
+        // continues on the next iteration, so no edge-triggered event is lost
+        let mut budget = Budget::new(stream.config.io_budget
+            .unwrap_or_else(|| scope.io_budget()));
+        monad = async_try!(stream.flush(monad, &mut budget, scope));
+        // Reading is paused while input buffer is above the high watermark,
+        // it's resumed on the next event if protocol consumed the data
+        while stream.reading() {
+            if budget.read == 0 && budget.reschedule(scope) {
+                break;
+            }
+            match stream.inbuf.read_from(&mut stream.socket) {
+                Ok(0) => { // Peer has shut down it's write side
+                    stream.eof = true;
+                    stream.readable = false;
+                    monad = async_try!(monad.and_then(|f| {
+                        stream.transport(|t| f.eof_received(t, scope))
+                    }));
+                }
+                Ok(bytes) => {
+                    budget.read = budget.read.saturating_sub(bytes);
+                    stream.last_read = scope.now();
+                    stream.last_active = scope.now();
+                    monad = async_try!(monad.and_then(|f| {
+                        stream.transport(|t| f.data_received(t, scope))
+                    }));
+                }
+                Err(ref e) if e.kind() == WouldBlock => {
+                    stream.readable = false;
+                    break;
+                }
+                Err(ref e) if e.kind() == Interrupted =>  { continue; }
+                Err(e) => {
+                    let err = Error::read(e);
+                    return Async::Error(err);
                 }
             }
         }
+        monad = async_try!(stream.flush(monad, &mut budget, scope));
+        let limit = stream.config.output_limit;
+        if limit.map_or(false, |x| stream.outbuf.len() > x) {
+            return Async::Error(Error::BufferLimit);
+        }
+        if let Some(reason) = stream.close() {
+            monad.done(|fsm| fsm.closed(reason, scope));
+            return Async::Stop;
+        }
         monad
         .map(|fsm| Stream(stream, fsm, PhantomData))
         .map_result(|()| None)
     }
+}
 
-    fn register(self, reg: &mut Registrator) -> Async<Self, ()> {
-        reg.register(&self.0.socket, EventSet::all(), PollOpt::edge());
-        Async::Continue(self, ())
+impl<C, S, P> EventMachine<C> for Stream<C, S, P>
+    where S: Socket + AsRawFd, P: Protocol<C>,
+{
+    fn ready(self, evset: EventSet, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        Stream::arm(if self.0.connecting.is_some() {
+            scope.wrap(Void::unreachable, |scope| self.establish(evset, scope))
+        } else {
+            scope.wrap(Void::unreachable, |scope| self.action(evset, scope))
+        }, scope)
     }
 
-    fn timeout(self, context: &mut C) -> Async<Self, Option<Self>> {
-        let Stream(stream, fsm, _) = self;
-        async_try!(fsm.timeout(context))
-        .map(|fsm| Stream(stream, fsm, PhantomData))
-        .map_result(|()| None)
+    fn register(mut self, reg: &mut dyn Registrator) -> Async<Self, ()> {
+        if let Some(timeout) = self.0.connect_timeout.take() {
+            self.0.connecting = Some(reg.now() + timeout);
+        }
+        match reg.register(&self.0.socket, EventSet::all(), PollOpt::edge()) {
+            Ok(()) => match self.0.deadline() {
+                Some(deadline) => Async::Timeout(self, deadline),
+                None => Async::Continue(self, ()),
+            },
+            Err(_) => Async::Error(Error::Register(Abort::RegisterFailed)),
+        }
     }
 
-    fn wakeup(self, context: &mut C) -> Async<Self, Option<Self>> {
+    fn timeout(self, timer: Timer, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        let Stream(mut stream, fsm, _) = self;
+        let now = scope.now();
+        if timer == Timer::Deadline
+            && stream.connecting.map_or(false, |dl| dl <= now)
+        {
+            return Stream::arm(Async::Error(Error::Timeout), scope);
+        }
+        Stream::arm(scope.wrap(Void::unreachable, |s| {
+            // Stream timers share the `Deadline` with the protocol, when
+            // both expire at once both callbacks are called
+            let expired = match timer {
+                Timer::Deadline => stream.expired(now),
+                Timer::Id(_) => None,
+            };
+            let protocol = expired.is_none()
+                || stream.protocol_deadline.map_or(false, |dl| dl <= now);
+            let mut monad = Async::Continue(fsm, ());
+            if let Some(kind) = expired {
+                stream.restart(kind, now);
+                monad = monad.and_then(|f| {
+                    stream.transport(|t| f.stream_timeout(kind, t, s))
+                });
+            }
+            if protocol {
+                monad = monad.and_then(|f| f.timeout(timer, s));
+            }
+            monad.map(|fsm| Stream(stream, fsm, PhantomData))
+            .and_then(|me| me.action(EventSet::none(), s))
+        }), scope)
+    }
+
+    fn wakeup(self, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
         let Stream(stream, fsm, _) = self;
-        async_try!(fsm.wakeup(context))
-        .map(|fsm| Stream(stream, fsm, PhantomData))
-        .map_result(|()| None)
+        Stream::arm(scope.wrap(Void::unreachable, |s| {
+            fsm.wakeup(s)
+            .map(|fsm| Stream(stream, fsm, PhantomData))
+            .and_then(|me| me.action(EventSet::none(), s))
+        }), scope)
+    }
+
+    /// Buffers and the connection state are kept for the new loop
+    fn detach(&mut self, reg: &mut dyn Registrator) {
+        reg.deregister(&self.0.socket).ok();
+    }
+
+    /// Calls `Protocol::shutdown`, connecting streams are just closed
+    fn shutdown(self, scope: &mut Scope<C, Self>)
+        -> Async<Self, Option<Self>>
+    {
+        let Stream(mut stream, fsm, _) = self;
+        if stream.connecting.is_some() {
+            return Async::Stop;
+        }
+        Stream::arm(scope.wrap(Void::unreachable, |s| {
+            stream.transport(|t| fsm.shutdown(t, s))
+            .map(|fsm| Stream(stream, fsm, PhantomData))
+            .and_then(|me| me.action(EventSet::none(), s))
+        }), scope)
     }
 }
 
 pub trait Protocol<C>: Sized {
-    fn accepted<S: Socket>(conn: &mut S, context: &mut C)
+    /// Called for each accepted connection
+    ///
+    /// Return `None` to close the connection. The socket may be inspected
+    /// here, e.g. with `transports::unix::peer_cred` for unix sockets.
+    fn accepted<S: Socket + AsRawFd>(conn: &mut S,
+        scope: &mut Scope<C, Void>)
         -> Option<Self>;
-    fn data_received(self, trans: &mut Transport, ctx: &mut C)
+    /// Called when connection initiated by `Stream::connect` is established
+    fn connected(self, _trans: &mut Transport, _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+    /// Returns buffer limits for this stream
+    ///
+    /// Called once when the stream is created
+    fn config(&self) -> StreamConfig {
+        StreamConfig::new()
+    }
+    fn data_received(self, trans: &mut Transport, scope: &mut Scope<C, Void>)
         -> Async<Self, ()>;
-    fn data_transferred(self, _trans: &mut Transport, _ctx: &mut C)
+    fn data_transferred(self, _trans: &mut Transport,
+        _scope: &mut Scope<C, Void>)
         -> Async<Self, ()> {
         Async::Continue(self, ())
     }
-    // TODO(tailhook) some error object should be here
-    fn error_happened(self, _err: io::Error, _ctx: &mut C) {}
-    fn eof_received(self, _ctx: &mut C) {}
+    /// Called when output buffer drains to the low watermark
+    ///
+    /// Use it to resume producing data which was paused because the output
+    /// buffer was too large.
+    fn output_drained(self, _trans: &mut Transport,
+        _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+    /// Called when connection is closed as requested via `Transport`
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<C, Void>) {}
+    /// Called when connection is closed because of the error
+    ///
+    /// This includes `Async::Error` returned by the protocol itself, so
+    /// there is no protocol instance and this is a static method. Stream
+    /// returns the error as `Async::Error` afterwards.
+    fn error_happened(_err: &Error, _scope: &mut Scope<C, Void>) {}
+    /// Called when the connection can't be accepted
+    ///
+    /// There is no protocol instance, so this is a static method. Can be
+    /// used to count errors in the context.
+    fn accept_failed(err: Error, _scope: &mut Scope<C, Void>) {
+        error!("Error accepting connection: {}", err);
+    }
+    /// Called when peer shuts down it's side of the connection
+    ///
+    /// Input buffer may still contain data not processed by
+    /// `data_received`. Default is to close the connection after sending
+    /// the output buffer. Don't call `close_after_flush()` to continue
+    /// writing to the half-closed connection.
+    fn eof_received(self, trans: &mut Transport, _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        trans.close_after_flush();
+        Async::Continue(self, ())
+    }
+    /// Called when a timeout configured in `StreamConfig` expires
+    ///
+    /// Default is to close the connection with `Error::Timeout`. Returning
+    /// `Continue` restarts the timer of this `kind`, so protocol may e.g.
+    /// send an error response and call `close_after_flush()`.
+    fn stream_timeout(self, _kind: TimeoutKind, _trans: &mut Transport,
+        _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Error(Error::Timeout)
+    }
 
-    fn timeout(self, _context: &mut C) -> Async<Self, ()> {
+    /// Called when deadline returned in `Async::Timeout` passes or when
+    /// the timer set by `Scope::set_timer` fires
+    fn timeout(self, _timer: Timer, _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+    fn wakeup(self, _scope: &mut Scope<C, Void>) -> Async<Self, ()> {
         Async::Continue(self, ())
     }
-    fn wakeup(self, _context: &mut C) -> Async<Self, ()> {
+    /// Called when the loop is shutting down gracefully
+    ///
+    /// Default is to close the connection after sending the output buffer.
+    /// The connection is dropped if it's not closed by the shutdown
+    /// deadline.
+    fn shutdown(self, trans: &mut Transport, _scope: &mut Scope<C, Void>)
+        -> Async<Self, ()>
+    {
+        trans.close_after_flush();
         Async::Continue(self, ())
     }
 }
 
+impl StreamConfig {
+    pub fn new() -> StreamConfig {
+        StreamConfig {
+            input_high_watermark: DEFAULT_INPUT_HIGH_WATERMARK,
+            output_low_watermark: 0,
+            output_limit: None,
+            io_budget: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+    /// Stop reading when input buffer has at least this number of bytes
+    pub fn input_high_watermark(&mut self, bytes: usize) -> &mut Self {
+        self.input_high_watermark = bytes;
+        self
+    }
+    /// Call `Protocol::output_drained` when output drains to this size
+    pub fn output_low_watermark(&mut self, bytes: usize) -> &mut Self {
+        self.output_low_watermark = bytes;
+        self
+    }
+    /// Close connection with `Error::BufferLimit` if more data is buffered
+    ///
+    /// Checked after each event, when output is sent as far as possible.
+    pub fn output_limit(&mut self, bytes: usize) -> &mut Self {
+        self.output_limit = Some(bytes);
+        self
+    }
+    /// Overrides `HandlerConfig::io_budget` for this stream
+    pub fn io_budget(&mut self, bytes: usize) -> &mut Self {
+        self.io_budget = Some(bytes);
+        self
+    }
+    /// Time out when nothing is read or written for this long
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+    /// Time out when nothing is read for this long
+    ///
+    /// The timer is stopped after EOF or when closing the connection.
+    pub fn read_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+    /// Time out when output is pending but nothing is sent for this long
+    pub fn write_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+}
+
+impl Default for StreamConfig {
+    fn default() -> StreamConfig {
+        StreamConfig::new()
+    }
+}
+
 impl<'a> Transport<'a> {
     pub fn new(inbuf: &'a mut  Buf, outbuf: &'a mut Buf) -> Transport<'a> {
         Transport {
-            inbuf: inbuf,
-            outbuf: outbuf,
+            inbuf,
+            outbuf,
+            request: None,
         }
     }
-    pub fn input<'x>(&'x mut self) -> &'x mut Buf {
+    pub fn input(&mut self) -> &mut Buf {
         self.inbuf
     }
-    pub fn output<'x>(&'x mut self) -> &'x mut Buf {
+    pub fn output(&mut self) -> &mut Buf {
         self.outbuf
     }
+    /// Closes the connection when output buffer is sent
+    ///
+    /// No more data is read from the connection.
+    pub fn close_after_flush(&mut self) {
+        self.request = max(self.request, Some(Request::CloseAfterFlush));
+    }
+    /// Shuts down the write side when output buffer is sent
+    ///
+    /// Data is still read, so this is useful for protocols where the peer
+    /// replies after receiving EOF. Output written later is discarded.
+    pub fn shutdown_write(&mut self) {
+        self.request = max(self.request, Some(Request::ShutdownWrite));
+    }
+    /// Closes the connection immediately discarding the output buffer
+    pub fn abort(&mut self) {
+        self.request = max(self.request, Some(Request::Abort));
+    }
+    /// Same as `abort()` but sets `SO_LINGER` to zero, so peer gets RST
+    pub fn reset(&mut self) {
+        self.request = max(self.request, Some(Request::Reset));
+    }
 }
 
 #[test]
@@ -214,3 +753,574 @@ fn test_transport() {
     }
     assert_eq!(&inbuf[..], b"bar");
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::net::{TcpListener, TcpStream as StdStream, Shutdown};
+
+    use time::{Duration, SteadyTime};
+    use mio::{EventLoop, EventSet, PollOpt, Evented};
+    use mio::tcp::{TcpStream, TcpListener as MioListener};
+
+    use {Async, EventMachine, Handler, Scope, Void, Error, Notifier};
+    use handler::Registrator;
+    use transports::StreamSocket;
+    use transports::accept::Serve;
+    use super::{Stream, Protocol, Transport, StreamConfig, CloseReason};
+    use super::TimeoutKind;
+
+    #[derive(Default)]
+    struct Context {
+        connected: bool,
+        error: Option<String>,
+        received: usize,
+        drained: bool,
+        bytes: usize,
+        closed: Option<CloseReason>,
+        half_close: bool,
+        timed_out: Option<TimeoutKind>,
+    }
+
+    struct Hello;
+
+    impl Protocol<Context> for Hello {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            None
+        }
+        fn connected(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.connected = true;
+            trans.output().extend(b"hello");
+            Async::Continue(self, ())
+        }
+        fn data_received(self, _trans: &mut Transport,
+            _scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            Async::Continue(self, ())
+        }
+        fn data_transferred(self, _trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.shutdown_loop();
+            Async::Continue(self, ())
+        }
+        fn error_happened(err: &Error, scope: &mut Scope<Context, Void>)
+        {
+            scope.error = Some(format!("{:?}", err));
+            scope.shutdown_loop();
+        }
+    }
+
+    type Client = Stream<Context, TcpStream, Hello>;
+
+    /// Replies "hello" to every chunk of data and never consumes input
+    struct Greedy;
+
+    impl Protocol<Context> for Greedy {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Greedy)
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.input_high_watermark(1);
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.received += 1;
+            trans.output().extend(b"hello");
+            Async::Continue(self, ())
+        }
+        fn output_drained(self, _trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.drained = true;
+            Async::Continue(self, ())
+        }
+    }
+
+    /// Consumes all input with the budget of a single read per event
+    struct Slow;
+
+    impl Protocol<Context> for Slow {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Slow)
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.io_budget(1);
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            let len = trans.input().len();
+            trans.input().consume(len);
+            scope.received += 1;
+            scope.bytes += len;
+            Async::Continue(self, ())
+        }
+    }
+
+    #[test]
+    fn io_budget() {
+        const TOTAL: usize = 65536;
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = StdStream::connect(lst.local_addr().unwrap())
+            .unwrap();
+        let writer = thread::spawn(move || {
+            client.write_all(&[0u8; TOTAL]).unwrap();
+            client
+        });
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let serve: Serve<_, _, Stream<_, TcpStream, Slow>> =
+            Serve::new(lst);
+        handler.add_root(&mut eloop, serve).unwrap();
+        while handler.context().bytes < TOTAL {
+            let before = handler.context().received;
+            eloop.run_once(&mut handler).unwrap();
+            // One read on the edge event and one on the rescheduled event
+            assert!(handler.context().received - before <= 2);
+        }
+        assert!(handler.context().received > 2);
+        writer.join().unwrap();
+    }
+
+    /// Replies "bye" and closes the connection the way the client asks
+    struct Closer;
+
+    impl Protocol<Context> for Closer {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Closer)
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.received += 1;
+            let cmd = trans.input()[..][0];
+            let len = trans.input().len();
+            trans.input().consume(len);
+            trans.output().extend(b"bye");
+            match cmd {
+                b'c' => trans.close_after_flush(),
+                b's' => trans.shutdown_write(),
+                b'r' => trans.reset(),
+                _ => return Async::Error(Error::protocol("bad command")),
+            }
+            Async::Continue(self, ())
+        }
+        fn closed(self, reason: CloseReason,
+            scope: &mut Scope<Context, Void>)
+        {
+            scope.closed = Some(reason);
+        }
+        fn error_happened(err: &Error, scope: &mut Scope<Context, Void>) {
+            scope.error = Some(err.to_string());
+        }
+    }
+
+    type CloserHandler = Handler<Context,
+        Serve<Context, MioListener, Stream<Context, TcpStream, Closer>>>;
+
+    fn serve_closer(cmd: &[u8])
+        -> (StdStream, EventLoop<CloserHandler>, CloserHandler)
+    {
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = StdStream::connect(lst.local_addr().unwrap())
+            .unwrap();
+        client.write_all(cmd).unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        handler.add_root(&mut eloop, Serve::new(lst)).unwrap();
+        while handler.context().received == 0 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        (client, eloop, handler)
+    }
+
+    #[test]
+    fn close_after_flush() {
+        let (mut client, _eloop, handler) = serve_closer(b"c");
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"bye");
+        assert_eq!(handler.context().closed, Some(CloseReason::Flushed));
+    }
+
+    #[test]
+    fn shutdown_write() {
+        let (mut client, mut eloop, mut handler) = serve_closer(b"s");
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"bye");
+        // Connection is still readable
+        client.write_all(b"x").unwrap();
+        while handler.context().received == 1 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(handler.context().closed, None);
+    }
+
+    #[test]
+    fn protocol_error() {
+        let (mut client, _eloop, handler) = serve_closer(b"x");
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"");
+        assert_eq!(handler.context().closed, None);
+        assert_eq!(handler.context().error.as_ref().unwrap(),
+            "protocol error: bad command");
+        assert_eq!(handler.errors(), 1);
+    }
+
+    #[test]
+    fn reset() {
+        let (mut client, _eloop, handler) = serve_closer(b"r");
+        let mut buf = Vec::new();
+        let err = client.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(handler.context().closed, Some(CloseReason::Reset));
+    }
+
+    /// Echoes input and replies "eof" on end of stream
+    struct Echo;
+
+    impl Protocol<Context> for Echo {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Echo)
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.received += 1;
+            let len = trans.input().len();
+            let Transport { ref mut inbuf, ref mut outbuf, .. } = *trans;
+            outbuf.extend(&inbuf[..]);
+            inbuf.consume(len);
+            Async::Continue(self, ())
+        }
+        fn eof_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            trans.output().extend(b"eof");
+            if !scope.half_close {
+                trans.close_after_flush();
+            }
+            Async::Continue(self, ())
+        }
+        fn closed(self, reason: CloseReason,
+            scope: &mut Scope<Context, Void>)
+        {
+            scope.closed = Some(reason);
+        }
+    }
+
+    type EchoHandler = Handler<Context,
+        Serve<Context, MioListener, Stream<Context, TcpStream, Echo>>>;
+
+    fn serve_echo(data: &[u8], half_close: bool)
+        -> (StdStream, EventLoop<EchoHandler>, EchoHandler)
+    {
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = StdStream::connect(lst.local_addr().unwrap())
+            .unwrap();
+        client.write_all(data).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        handler.context_mut().half_close = half_close;
+        handler.add_root(&mut eloop, Serve::new(lst)).unwrap();
+        while handler.context().received == 0 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        (client, eloop, handler)
+    }
+
+    #[test]
+    fn responses_sent_before_eof() {
+        let (mut client, mut eloop, mut handler) = serve_echo(b"hello", false);
+        while handler.context().closed.is_none() {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"helloeof");
+        assert_eq!(handler.context().closed, Some(CloseReason::Flushed));
+    }
+
+    #[test]
+    fn half_closed() {
+        let (mut client, mut eloop, mut handler) = serve_echo(b"hello", true);
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"helloeof");
+        for _ in 0..3 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(handler.context().closed, None);
+    }
+
+    #[test]
+    fn input_high_watermark() {
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = StdStream::connect(lst.local_addr().unwrap())
+            .unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let serve: Serve<_, _, Stream<_, TcpStream, Greedy>> =
+            Serve::new(lst);
+        handler.add_root(&mut eloop, serve).unwrap();
+        while handler.context().received == 0 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert!(handler.context().drained);
+        // Input buffer is full so new data must not be read
+        client.write_all(b"world").unwrap();
+        for _ in 0..3 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(handler.context().received, 1);
+    }
+
+    #[test]
+    fn connect() {
+        let lst = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = lst.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0u8; 5];
+            lst.accept().unwrap().0.read_exact(&mut buf).unwrap();
+            buf
+        });
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let client: Client = Stream::connect(&addr, Hello).unwrap();
+        handler.add_root(&mut eloop, client).unwrap();
+        eloop.run(&mut handler).unwrap();
+        assert!(handler.context().connected);
+        assert!(handler.context().error.is_none());
+        assert_eq!(&server.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn connection_refused() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap()
+            .local_addr().unwrap();  // listener is closed immediately
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let client: Client = Stream::connect(&addr, Hello).unwrap();
+        handler.add_root(&mut eloop, client).unwrap();
+        eloop.run(&mut handler).unwrap();
+        assert!(!handler.context().connected);
+        let err = handler.context().error.clone().unwrap();
+        assert!(err.starts_with("Connect(") && err.contains("Refused"),
+            "{}", err);
+        assert_eq!(handler.errors(), 1);
+    }
+
+    /// Fails to register anything
+    struct Failing;
+
+    impl Registrator for Failing {
+        fn register(&mut self, _io: &dyn Evented, _interest: EventSet,
+            _opt: PollOpt)
+            -> io::Result<()>
+        {
+            Err(io::ErrorKind::Other.into())
+        }
+        fn reregister(&mut self, _io: &dyn Evented, _interest: EventSet,
+            _opt: PollOpt)
+            -> io::Result<()>
+        {
+            Err(io::ErrorKind::Other.into())
+        }
+        fn deregister(&mut self, _io: &dyn Evented) -> io::Result<()> {
+            Ok(())
+        }
+        fn notifier(&mut self) -> Notifier {
+            unreachable!();
+        }
+        fn now(&self) -> SteadyTime {
+            SteadyTime::now()
+        }
+    }
+
+    #[test]
+    fn register_failed() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap()
+            .local_addr().unwrap();
+        let client: Client = Stream::connect(&addr, Hello).unwrap();
+        assert!(matches!(client.register(&mut Failing),
+            Async::Error(Error::Register(_))));
+    }
+
+    /// Writes a lot of data on each request
+    struct Flood;
+
+    impl Protocol<Context> for Flood {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Flood)
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.output_limit(1024);
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.received += 1;
+            trans.output().extend(&vec![0u8; 1 << 24]);
+            Async::Continue(self, ())
+        }
+        fn error_happened(err: &Error, scope: &mut Scope<Context, Void>)
+        {
+            scope.error = Some(format!("{:?}", err));
+        }
+    }
+
+    #[test]
+    fn output_limit() {
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = StdStream::connect(lst.local_addr().unwrap())
+            .unwrap();
+        client.write_all(b"x").unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let serve: Serve<_, _, Stream<_, TcpStream, Flood>> =
+            Serve::new(lst);
+        handler.add_root(&mut eloop, serve).unwrap();
+        while handler.context().received == 0 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(handler.context().error.as_ref().unwrap(), "BufferLimit");
+        assert_eq!(handler.errors(), 1);
+    }
+
+    /// Replies "timeout" if client sends nothing, floods it otherwise
+    struct Impatient;
+
+    impl Protocol<Context> for Impatient {
+        fn accepted<S: StreamSocket>(_conn: &mut S,
+            _scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            Some(Impatient)
+        }
+        fn config(&self) -> StreamConfig {
+            let mut cfg = StreamConfig::new();
+            cfg.idle_timeout(Duration::seconds(10));
+            cfg.read_timeout(Duration::milliseconds(100));
+            cfg.write_timeout(Duration::milliseconds(100));
+            cfg
+        }
+        fn data_received(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.received += 1;
+            let len = trans.input().len();
+            trans.input().consume(len);
+            trans.output().extend(&vec![0u8; 1 << 24]);
+            trans.close_after_flush();
+            Async::Continue(self, ())
+        }
+        fn stream_timeout(self, kind: TimeoutKind, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.timed_out = Some(kind);
+            match kind {
+                TimeoutKind::Read => {
+                    trans.output().extend(b"timeout");
+                    trans.close_after_flush();
+                    Async::Continue(self, ())
+                }
+                _ => Async::Error(Error::Timeout),
+            }
+        }
+        fn closed(self, reason: CloseReason,
+            scope: &mut Scope<Context, Void>)
+        {
+            scope.closed = Some(reason);
+        }
+    }
+
+    type ImpatientHandler = Handler<Context,
+        Serve<Context, MioListener, Stream<Context, TcpStream, Impatient>>>;
+
+    fn serve_impatient() -> (StdStream, EventLoop<ImpatientHandler>,
+        ImpatientHandler)
+    {
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdStream::connect(lst.local_addr().unwrap()).unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let serve: Serve<_, _, Stream<_, TcpStream, Impatient>> =
+            Serve::new(lst);
+        handler.add_root(&mut eloop, serve).unwrap();
+        (client, eloop, handler)
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (mut client, mut eloop, mut handler) = serve_impatient();
+        while handler.context().closed.is_none() {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(handler.context().timed_out, Some(TimeoutKind::Read));
+        assert_eq!(handler.context().closed, Some(CloseReason::Flushed));
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"timeout");
+    }
+
+    #[test]
+    fn write_timeout() {
+        let (mut client, mut eloop, mut handler) = serve_impatient();
+        client.write_all(b"x").unwrap();
+        // Client doesn't read the response so output can't be flushed
+        while handler.errors() == 0 {
+            eloop.run_once(&mut handler).unwrap();
+        }
+        assert_eq!(handler.context().received, 1);
+        assert_eq!(handler.context().timed_out, Some(TimeoutKind::Write));
+        assert_eq!(handler.context().closed, None);
+    }
+}
@@ -0,0 +1,205 @@
+//! Helpers for unix domain sockets
+//!
+//! Both `mio::unix::UnixListener` and `mio::unix::UnixStream` work with
+//! `accept::Serve` and `stream::Stream` the same way as TCP sockets do.
+use std::io;
+use std::fs;
+#[cfg(target_os="linux")] use std::mem::size_of;
+use std::path::Path;
+use std::io::ErrorKind::{NotFound, AddrInUse};
+#[cfg(target_os="linux")] use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdStream;
+
+use libc;
+use mio::unix::UnixListener;
+
+
+/// Credentials of the process at the other side of the unix socket
+#[cfg(target_os="linux")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Binds listening unix socket at `path`
+///
+/// Stale socket file left by a previous process is removed. If some process
+/// still accepts connections on it `AddrInUse` error is returned.
+pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {
+            if StdStream::connect(path).is_ok() {
+                return Err(io::Error::new(AddrInUse,
+                    "unix socket is in use by another process"));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {}  // Not a socket, let bind report an error
+        Err(ref e) if e.kind() == NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Same as `bind` but also sets permissions of the socket file
+///
+/// Note the socket is accessible with default permissions (as set by
+/// umask) for a short period of time between bind and chmod.
+pub fn bind_with_mode<P: AsRef<Path>>(path: P, mode: u32)
+    -> io::Result<UnixListener>
+{
+    let sock = bind(path.as_ref())?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(sock)
+}
+
+/// Returns credentials of the peer process (`SO_PEERCRED`)
+///
+/// These are credentials at the time of `connect()` of the peer.
+#[cfg(target_os="linux")]
+pub fn peer_cred<S: AsRawFd>(sock: &S) -> io::Result<PeerCred> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred { pid: cred.pid, uid: cred.uid, gid: cred.gid })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::env;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::io::ErrorKind::AddrInUse;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixStream as StdStream, UnixListener as StdLst};
+
+    use libc;
+    use mio::EventLoop;
+    use mio::unix::UnixStream;
+
+    use {Async, Handler, Scope, Void};
+    use transports::StreamSocket;
+    use transports::accept::Serve;
+    use transports::stream::{Stream, Protocol, Transport};
+    use super::{bind, bind_with_mode};
+    #[cfg(target_os="linux")] use super::{peer_cred, PeerCred};
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rotor-test-{}-{}.sock",
+            name, unsafe { libc::getpid() }));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    #[derive(Default)]
+    struct Context {
+        #[cfg(target_os="linux")]
+        peer: Option<PeerCred>,
+        connected: bool,
+    }
+
+    struct Admin;
+
+    impl Protocol<Context> for Admin {
+        #[cfg_attr(not(target_os="linux"), allow(unused_variables))]
+        fn accepted<S: StreamSocket + AsRawFd>(conn: &mut S,
+            scope: &mut Scope<Context, Void>)
+            -> Option<Self>
+        {
+            #[cfg(target_os="linux")]
+            {
+                scope.peer = Some(peer_cred(conn).unwrap());
+            }
+            scope.shutdown_loop();
+            Some(Admin)
+        }
+        fn connected(self, trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.connected = true;
+            trans.output().extend(b"hello");
+            Async::Continue(self, ())
+        }
+        fn data_received(self, _trans: &mut Transport,
+            _scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            Async::Continue(self, ())
+        }
+        fn data_transferred(self, _trans: &mut Transport,
+            scope: &mut Scope<Context, Void>)
+            -> Async<Self, ()>
+        {
+            scope.shutdown_loop();
+            Async::Continue(self, ())
+        }
+    }
+
+    #[test]
+    fn replace_stale_socket() {
+        let path = socket_path("stale");
+        drop(StdLst::bind(&path).unwrap());
+        assert!(path.exists());
+        let lst = bind(&path).unwrap();
+        assert_eq!(bind(&path).unwrap_err().kind(), AddrInUse);
+        drop(lst);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn socket_mode() {
+        let path = socket_path("mode");
+        let _lst = bind_with_mode(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os="linux")]
+    fn serve_peer_cred() {
+        let path = socket_path("serve");
+        let lst = bind(&path).unwrap();
+        let _client = StdStream::connect(&path).unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let serve: Serve<_, _, Stream<_, UnixStream, Admin>> = Serve::new(lst);
+        handler.add_root(&mut eloop, serve).unwrap();
+        eloop.run(&mut handler).unwrap();
+        let peer = handler.context().peer.unwrap();
+        assert_eq!(peer.pid, unsafe { libc::getpid() });
+        assert_eq!(peer.uid, unsafe { libc::getuid() });
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connect_unix() {
+        let path = socket_path("connect");
+        let lst = StdLst::bind(&path).unwrap();
+        let mut eloop = EventLoop::new().unwrap();
+        let mut handler = Handler::new(Context::default(), &mut eloop);
+        let client: Stream<_, UnixStream, Admin> =
+            Stream::connect_unix(&path, Admin).unwrap();
+        handler.add_root(&mut eloop, client).unwrap();
+        eloop.run(&mut handler).unwrap();
+        assert!(handler.context().connected);
+        let mut buf = [0u8; 5];
+        lst.accept().unwrap().0.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        fs::remove_file(&path).unwrap();
+    }
+}